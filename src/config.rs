@@ -1,5 +1,9 @@
 use anyhow::Context;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+use crate::connections::Permissions;
+use crate::gateways::GatewayPolicy;
+use crate::payments::BudgetRenewal;
 
 #[derive(Parser, Debug, Clone)]
 #[command(version, author, about)]
@@ -8,18 +12,147 @@ pub struct Config {
     #[clap(default_value_t = String::from("keys.json"), long)]
     /// Location of keys file
     pub keys_file: String,
-    #[clap(long, required = true)]
-    /// Relay to use for communicating
-    pub relay: String,
-    /// Max invoice payment amount, in satoshis
+    #[clap(default_value_t = String::from("connections.json"), long)]
+    /// Location of wallet connections file
+    pub connections_file: String,
+    #[clap(default_value_t = String::from("payments"), long)]
+    /// Directory to persist the payments of each connection in
+    pub payments_dir: String,
+    #[clap(default_value_t = String::from("requests"), long)]
+    /// Directory to record handled requests and their responses in
+    pub requests_dir: String,
+    #[clap(default_value_t = String::from("settlements.json"), long)]
+    /// Location of the file recording when payments settled
+    pub settlements_file: String,
+    #[clap(long = "relay")]
+    /// Relay to use for communicating, can be given multiple times
+    pub relays: Vec<String>,
+    #[clap(long)]
+    /// File with more relays to use, one per line
+    pub relays_file: Option<String>,
+    /// Default max invoice payment amount for new connections, in satoshis
     #[clap(default_value_t = 100_000, long)]
     pub max_amount: u64,
-    /// Max payment amount per day, in satoshis
-    #[clap(default_value_t = 100_000, long)]
-    pub daily_limit: u64,
+    /// Default max payment amount per budget period for new connections, in
+    /// satoshis
+    #[clap(default_value_t = 100_000, long, alias = "daily-limit")]
+    pub budget: u64,
+    /// Default budget renewal period for new connections
+    #[clap(default_value_t = BudgetRenewal::Daily, long, value_enum)]
+    pub budget_renewal: BudgetRenewal,
+    /// Reset budgets at the start of each calendar period (UTC) instead of
+    /// using a sliding window
+    #[clap(long)]
+    pub calendar_budget: bool,
+    /// Id of a lightning gateway to use, can be given multiple times to fall
+    /// back to the next one when a payment fails
+    #[clap(long = "gateway")]
+    pub gateways: Vec<String>,
+    /// How to pick lightning gateways when none are given with --gateway
+    #[clap(default_value_t = GatewayPolicy::Vetted, long, value_enum)]
+    pub gateway_policy: GatewayPolicy,
+    /// Max gateway fee for outgoing payments, in satoshis
+    #[clap(default_value_t = 10, long)]
+    pub max_fee: u64,
+    /// Max gateway fee for outgoing payments, in percent of the amount. Fees
+    /// up to the larger of --max-fee and this are accepted
+    #[clap(default_value_t = 1.0, long)]
+    pub max_fee_percent: f64,
+    /// Max age of requests, in seconds, older requests are rejected instead
+    /// of executed. 0 disables the check
+    #[clap(default_value_t = 600, long)]
+    pub max_request_age: u64,
+    /// Max payments of a multi_pay request made at the same time
+    #[clap(default_value_t = 4, long)]
+    pub multi_pay_concurrency: usize,
+    /// Cap the reported balance to the connection's remaining budget
+    #[clap(long, alias = "cap-balance-to-limit")]
+    pub cap_balance_to_budget: bool,
     #[clap(long, required = true)]
     /// Datadir for multimint
     pub data_dir: String,
+    #[clap(long)]
+    /// Invite code of a federation to join on startup
+    pub invite_code: Option<String>,
+    #[clap(long)]
+    /// Id of the federation serving connections that aren't bound to one,
+    /// required when more than one federation is joined
+    pub federation_id: Option<String>,
+    #[clap(long)]
+    /// Also print wallet connect uris as QR codes
+    pub qr: bool,
+    #[clap(long)]
+    /// Hide the secret of the wallet connect uris printed on startup
+    pub hide_secret: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Add a wallet connection for a new app
+    AddConnection {
+        /// Name of the connection
+        name: String,
+        /// Max invoice payment amount, in satoshis, defaults to --max-amount
+        #[clap(long)]
+        max_amount: Option<u64>,
+        /// Max payment amount per budget period, in satoshis, defaults to
+        /// --budget
+        #[clap(long)]
+        budget: Option<u64>,
+        /// Budget renewal period, defaults to --budget-renewal
+        #[clap(long, value_enum)]
+        budget_renewal: Option<BudgetRenewal>,
+        /// Reset the budget at the start of each calendar period, defaults to
+        /// --calendar-budget
+        #[clap(long)]
+        calendar_budget: bool,
+        /// Methods the app may call
+        #[clap(default_value_t = Permissions::Full, long, value_enum)]
+        permissions: Permissions,
+        /// Id of the federation the app's wallet is in, defaults to
+        /// --federation-id
+        #[clap(long)]
+        federation_id: Option<String>,
+        /// Id of another federation the app may pay from when its own is
+        /// short, can be given multiple times in order of preference
+        #[clap(long = "spend-federation")]
+        spend_federations: Vec<String>,
+    },
+    /// Print the wallet connect uri of a connection
+    Uri {
+        /// Name of the connection
+        #[clap(default_value_t = String::from("default"))]
+        name: String,
+    },
+}
+
+impl Config {
+    /// Adds the relays from the relays file to the ones given on the command
+    /// line, there must be at least one relay in total.
+    pub fn load_relays_file(&mut self) -> Result<(), anyhow::Error> {
+        if let Some(relays_file) = &self.relays_file {
+            let relays = std::fs::read_to_string(relays_file)
+                .with_context(|| format!("Could not read relays file {relays_file}"))?;
+            for relay in relays.lines().map(str::trim) {
+                if !relay.is_empty()
+                    && !relay.starts_with('#')
+                    && !self.relays.iter().any(|r| r == relay)
+                {
+                    self.relays.push(relay.to_string());
+                }
+            }
+        }
+
+        if self.relays.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No relays configured, pass --relay or --relays-file"
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 fn home_directory() -> Result<String, anyhow::Error> {
@@ -0,0 +1,250 @@
+use std::fs::{create_dir_all, File};
+use std::io::{BufReader, Write};
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context};
+use clap::ValueEnum;
+use nostr::nips::nip47::Method;
+use nostr::{Keys, PublicKey, Url};
+use nostr_sdk::SecretKey;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::keys::Nip47Keys;
+use crate::payments::{BudgetPeriod, BudgetRenewal};
+use crate::METHODS;
+
+/// Presets of the methods a connection may call.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Permissions {
+    /// Every supported method
+    #[default]
+    Full,
+    /// Look at the balance and transactions, but not pay or receive
+    ReadOnly,
+    /// Create and look up invoices, but not pay
+    ReceiveOnly,
+}
+
+impl Permissions {
+    /// Returns the methods allowed by the preset, `get_info` is always
+    /// included so apps can find out what they may do.
+    pub fn methods(&self) -> Vec<Method> {
+        match self {
+            Permissions::Full => METHODS.to_vec(),
+            Permissions::ReadOnly => vec![
+                Method::GetInfo,
+                Method::GetBalance,
+                Method::LookupInvoice,
+                Method::ListTransactions,
+            ],
+            Permissions::ReceiveOnly => {
+                vec![Method::GetInfo, Method::MakeInvoice, Method::LookupInvoice]
+            }
+        }
+    }
+}
+
+fn default_methods() -> Vec<Method> {
+    METHODS.to_vec()
+}
+
+/// A wallet connection handed out to a single app, with its own client key
+/// and spending limits.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Connection {
+    pub name: String,
+    pub client_key: SecretKey,
+    /// Max invoice payment amount, in satoshis
+    pub max_amount: u64,
+    /// Max payment amount per budget period, in satoshis
+    #[serde(alias = "daily_limit")]
+    pub budget: u64,
+    #[serde(default)]
+    pub budget_renewal: BudgetRenewal,
+    /// Whether the budget resets at the start of each calendar period instead
+    /// of using a sliding window
+    #[serde(default)]
+    pub calendar_budget: bool,
+    /// Methods the connection's app may call
+    #[serde(default = "default_methods")]
+    pub methods: Vec<Method>,
+    /// Id of the federation the connection's wallet is in, `None` for the
+    /// default federation
+    #[serde(default)]
+    pub federation_id: Option<String>,
+    /// Ids of other federations the connection may pay from when its own
+    /// federation is short, in order of preference
+    #[serde(default)]
+    pub spend_federations: Vec<String>,
+}
+
+impl Connection {
+    /// Creates a connection with a fresh client key.
+    pub fn new(
+        name: String,
+        max_amount: u64,
+        budget: u64,
+        budget_renewal: BudgetRenewal,
+        calendar_budget: bool,
+        methods: Vec<Method>,
+        federation_id: Option<String>,
+        spend_federations: Vec<String>,
+    ) -> Result<Self, anyhow::Error> {
+        let client_key = Keys::generate();
+
+        Ok(Connection {
+            name,
+            client_key: nostr_sdk::SecretKey::from_slice(client_key.secret_key()?.as_ref())
+                .context("Failed to convert to nostr_sdk::SecretKey")?,
+            max_amount,
+            budget,
+            budget_renewal,
+            calendar_budget,
+            methods,
+            federation_id,
+            spend_federations,
+        })
+    }
+
+    /// Returns the wallet connect uri the connection's app connects with,
+    /// listing every relay we listen on.
+    pub fn uri(&self, server_key: PublicKey, relays: &[String]) -> Result<Url, anyhow::Error> {
+        let secret = self.client_key.display_secret().to_string();
+        wallet_connect_uri(server_key, relays, &secret)
+    }
+
+    /// Returns the wallet connect uri with the secret left out, for output
+    /// that may end up in logs.
+    pub fn redacted_uri(
+        &self,
+        server_key: PublicKey,
+        relays: &[String],
+    ) -> Result<Url, anyhow::Error> {
+        wallet_connect_uri(server_key, relays, "<hidden>")
+    }
+
+    /// Returns whether the connection's app may call `method`.
+    pub fn allows(&self, method: &Method) -> bool {
+        self.methods.contains(method)
+    }
+
+    /// Returns the period payments count toward the budget over.
+    pub fn budget_period(&self) -> BudgetPeriod {
+        BudgetPeriod {
+            renewal: self.budget_renewal,
+            calendar_aligned: self.calendar_budget,
+        }
+    }
+
+    /// Returns a new `Keys` instance using the client key.
+    pub fn client_keys(&self) -> Keys {
+        Keys::new(self.client_key.clone().into())
+    }
+
+    /// Returns the public key the connection's app signs requests with.
+    pub fn public_key(&self) -> PublicKey {
+        self.client_keys().public_key()
+    }
+}
+
+fn wallet_connect_uri(
+    server_key: PublicKey,
+    relays: &[String],
+    secret: &str,
+) -> Result<Url, anyhow::Error> {
+    let mut uri = Url::parse(&format!("nostr+walletconnect://{server_key}"))?;
+    {
+        let mut query = uri.query_pairs_mut();
+        for relay in relays {
+            Url::parse(relay).with_context(|| format!("Invalid relay url {relay}"))?;
+            query.append_pair("relay", relay);
+        }
+        query.append_pair("secret", secret);
+    }
+
+    Ok(uri)
+}
+
+/// Registry of all wallet connections, persisted as JSON.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Connections {
+    connections: Vec<Connection>,
+}
+
+impl Connections {
+    /// Retrieves connections from a file. If the file does not exist, a
+    /// `default` connection is created from the user key of `keys` and the
+    /// limits in `config`, so existing setups keep working.
+    pub fn load_or_default(
+        connections_file: PathBuf,
+        keys: &Nip47Keys,
+        config: &Config,
+    ) -> Result<Connections, anyhow::Error> {
+        match File::open(&connections_file) {
+            Ok(file) => {
+                let reader = BufReader::new(file);
+                serde_json::from_reader(reader).context("Could not parse JSON")
+            }
+            Err(_) => {
+                let connections = Connections {
+                    connections: vec![Connection {
+                        name: "default".to_string(),
+                        client_key: keys.user_key.clone(),
+                        max_amount: config.max_amount,
+                        budget: config.budget,
+                        budget_renewal: config.budget_renewal,
+                        calendar_budget: config.calendar_budget,
+                        methods: default_methods(),
+                        federation_id: None,
+                        spend_federations: Vec::new(),
+                    }],
+                };
+                connections.write_connections(&connections_file)?;
+                Ok(connections)
+            }
+        }
+    }
+
+    /// Serializes the connections and writes them to the specified path.
+    pub fn write_connections(&self, path: &PathBuf) -> Result<(), anyhow::Error> {
+        let json_str = serde_json::to_string(&self).context("Could not serialize data")?;
+
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).context("Could not create directory")?;
+        }
+
+        let mut file = File::create(path).context("Could not create file")?;
+        file.write_all(json_str.as_bytes())
+            .context("Could not write to file")?;
+
+        Ok(())
+    }
+
+    /// Adds a new connection, names must be unique.
+    pub fn add(&mut self, connection: Connection) -> Result<(), anyhow::Error> {
+        if self.get_by_name(&connection.name).is_some() {
+            return Err(anyhow!("Connection {} already exists", connection.name));
+        }
+
+        self.connections.push(connection);
+        Ok(())
+    }
+
+    /// Returns the connection with the given name.
+    pub fn get_by_name(&self, name: &str) -> Option<&Connection> {
+        self.connections.iter().find(|c| c.name == name)
+    }
+
+    /// Returns all connections.
+    pub fn iter(&self) -> impl Iterator<Item = &Connection> {
+        self.connections.iter()
+    }
+
+    /// Returns the connection whose app signs with the given public key.
+    pub fn get(&self, public_key: &PublicKey) -> Option<&Connection> {
+        self.connections
+            .iter()
+            .find(|c| &c.public_key() == public_key)
+    }
+}
@@ -0,0 +1,81 @@
+use anyhow::anyhow;
+use nostr::nips::{nip04, nip44};
+use nostr::{Event, PublicKey, SecretKey, Tag, TagKind};
+
+/// Encryption schemes for wallet connect events. Requests can use either and
+/// are answered with the scheme they were sent with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    Nip04,
+    Nip44V2,
+}
+
+impl Encryption {
+    /// Supported schemes, in order of preference.
+    pub const SUPPORTED: [Encryption; 2] = [Encryption::Nip44V2, Encryption::Nip04];
+
+    /// Name of the scheme as used in `encryption` tags.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encryption::Nip04 => "nip04",
+            Encryption::Nip44V2 => "nip44_v2",
+        }
+    }
+
+    /// Returns the `encryption` tag advertising the supported schemes.
+    pub fn info_tag() -> Tag {
+        let schemes = Encryption::SUPPORTED
+            .iter()
+            .map(|e| e.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        Tag::Generic(TagKind::Custom("encryption".to_string()), vec![schemes])
+    }
+
+    /// Determines the scheme of a request from its `encryption` tag. Requests
+    /// without one are NIP-04 unless the content isn't in the NIP-04 format.
+    pub fn from_event(event: &Event) -> anyhow::Result<Self> {
+        let tag = event
+            .tags
+            .iter()
+            .map(|t| t.as_vec())
+            .find(|t| t.first().map(String::as_str) == Some("encryption"));
+
+        match tag.as_ref().and_then(|t| t.get(1)).map(String::as_str) {
+            Some("nip04") => Ok(Encryption::Nip04),
+            Some("nip44_v2") => Ok(Encryption::Nip44V2),
+            Some(scheme) => Err(anyhow!("Unsupported encryption: {scheme}")),
+            None if event.content.contains("?iv=") => Ok(Encryption::Nip04),
+            None => Ok(Encryption::Nip44V2),
+        }
+    }
+
+    pub fn encrypt(
+        &self,
+        secret_key: &SecretKey,
+        public_key: &PublicKey,
+        content: String,
+    ) -> anyhow::Result<String> {
+        Ok(match self {
+            Encryption::Nip04 => nip04::encrypt(secret_key, public_key, content)?,
+            Encryption::Nip44V2 => {
+                nip44::encrypt(secret_key, public_key, content, nip44::Version::V2)?
+            }
+        })
+    }
+
+    pub fn decrypt(
+        &self,
+        secret_key: &SecretKey,
+        public_key: &PublicKey,
+        content: &str,
+    ) -> anyhow::Result<String> {
+        Ok(match self {
+            Encryption::Nip04 => nip04::decrypt(secret_key, public_key, content)?,
+            Encryption::Nip44V2 => nip44::decrypt(secret_key, public_key, content)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests;
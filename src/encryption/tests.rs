@@ -0,0 +1,42 @@
+use nostr::{EventBuilder, Keys, Kind};
+
+use super::*;
+
+fn request(content: &str, scheme: Option<&str>) -> Event {
+    let tags = scheme.map(|scheme| {
+        Tag::Generic(
+            TagKind::Custom("encryption".to_string()),
+            vec![scheme.to_string()],
+        )
+    });
+    EventBuilder::new(Kind::WalletConnectRequest, content, tags)
+        .to_event(&Keys::generate())
+        .unwrap()
+}
+
+#[test]
+fn tag_selects_scheme() {
+    let event = request("ciphertext?iv=abc", Some("nip44_v2"));
+    assert_eq!(Encryption::from_event(&event).unwrap(), Encryption::Nip44V2);
+
+    let event = request("ciphertext", Some("nip04"));
+    assert_eq!(Encryption::from_event(&event).unwrap(), Encryption::Nip04);
+}
+
+#[test]
+fn unsupported_scheme_is_rejected() {
+    let event = request("ciphertext", Some("nip44_v3"));
+    assert!(Encryption::from_event(&event).is_err());
+}
+
+#[test]
+fn nip04_content_without_tag() {
+    let event = request("ciphertext?iv=abc", None);
+    assert_eq!(Encryption::from_event(&event).unwrap(), Encryption::Nip04);
+}
+
+#[test]
+fn other_content_without_tag_is_nip44() {
+    let event = request("ciphertext", None);
+    assert_eq!(Encryption::from_event(&event).unwrap(), Encryption::Nip44V2);
+}
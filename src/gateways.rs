@@ -0,0 +1,143 @@
+use anyhow::bail;
+use clap::ValueEnum;
+use fedimint_client::ClientHandleArc;
+use fedimint_ln_client::LightningClientModule;
+use fedimint_ln_common::LightningGateway;
+use lightning_invoice::{Bolt11Invoice, RoutingFees};
+use log::warn;
+use tokio::spawn;
+
+use crate::config::Config;
+
+/// How lightning gateways are picked when no gateway is pinned with
+/// `--gateway`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum GatewayPolicy {
+    /// Vetted gateways first, then the others
+    #[default]
+    Vetted,
+    /// Only gateways vetted by the federation
+    VettedOnly,
+    /// The gateways charging the least for the amount first
+    Cheapest,
+}
+
+/// Returns the fee a gateway charges for routing `msats`.
+pub fn routing_fee_msats(fees: &RoutingFees, msats: u64) -> u64 {
+    fees.base_msat as u64 + msats * fees.proportional_millionths as u64 / 1_000_000
+}
+
+/// Returns the max fee accepted for paying `msats`, the larger of the
+/// absolute and the percentage cap.
+pub fn max_fee_msats(config: &Config, msats: u64) -> u64 {
+    let percent_msats = (msats as f64 * config.max_fee_percent / 100.0) as u64;
+    (config.max_fee * 1_000).max(percent_msats)
+}
+
+/// Keeps the federation's gateways up to date. The federation client only
+/// fetches them once, so without this gateways are picked from a stale list
+/// with stale fees.
+pub fn watch_gateways(client: ClientHandleArc) {
+    spawn(async move {
+        let lightning_module = client.get_first_module::<LightningClientModule>();
+        if let Err(e) = lightning_module.update_gateway_cache().await {
+            warn!(
+                "Error fetching the gateways of federation {}: {e}",
+                client.federation_id()
+            );
+        }
+        // refetches them whenever a gateway's registration is about to expire
+        lightning_module
+            .update_gateway_cache_continuously(|gateways| async move { gateways })
+            .await;
+    });
+}
+
+/// Returns whether the federation pays the invoice itself, without a
+/// gateway: the invoice carries the federation's internal payment markers, or
+/// routes back to the federation through one of its gateways. Like the
+/// federation client, only the first route hint is checked.
+pub async fn pays_internally(client: &ClientHandleArc, invoice: &Bolt11Invoice) -> bool {
+    let Some((node_pub_key, channel_id)) = invoice
+        .route_hints()
+        .first()
+        .and_then(|route_hint| route_hint.0.last())
+        .map(|hop| (hop.src_node_id.serialize(), hop.short_channel_id))
+    else {
+        return false;
+    };
+
+    if let Ok((marker_pub_key, marker_channel_id)) = client.get_internal_payment_markers() {
+        if node_pub_key == marker_pub_key.serialize() && channel_id == marker_channel_id {
+            return true;
+        }
+    }
+
+    let lightning_module = client.get_first_module::<LightningClientModule>();
+    lightning_module
+        .list_gateways()
+        .await
+        .iter()
+        .any(|gateway| {
+            node_pub_key == gateway.info.node_pub_key.serialize()
+                && channel_id == gateway.info.mint_channel_id
+        })
+}
+
+/// Returns the gateways to route `msats` through in the order they should be
+/// tried: the pinned gateways if any are configured, otherwise the
+/// federation's gateways ordered by the gateway policy. Fails if there is no
+/// gateway to use.
+pub async fn select_gateways(
+    lightning_module: &LightningClientModule,
+    config: &Config,
+    msats: u64,
+) -> anyhow::Result<Vec<LightningGateway>> {
+    let gateways = lightning_module.list_gateways().await;
+
+    if !config.gateways.is_empty() {
+        let pinned: Vec<_> = config
+            .gateways
+            .iter()
+            .filter_map(|id| {
+                gateways
+                    .iter()
+                    .find(|gateway| &gateway.info.gateway_id.to_string() == id)
+            })
+            .map(|gateway| gateway.info.clone())
+            .collect();
+        if pinned.is_empty() {
+            bail!(
+                "None of the pinned gateways is available: {}",
+                config.gateways.join(", ")
+            );
+        }
+        return Ok(pinned);
+    }
+
+    let mut gateways = match config.gateway_policy {
+        GatewayPolicy::VettedOnly => gateways.into_iter().filter(|g| g.vetted).collect(),
+        _ => gateways,
+    };
+    match config.gateway_policy {
+        GatewayPolicy::Cheapest => gateways.sort_by_key(|gateway| {
+            (
+                routing_fee_msats(&gateway.info.fees, msats),
+                !gateway.vetted,
+            )
+        }),
+        _ => gateways.sort_by_key(|gateway| !gateway.vetted),
+    }
+
+    if gateways.is_empty() {
+        match config.gateway_policy {
+            GatewayPolicy::VettedOnly => bail!("The federation has no vetted gateway"),
+            _ => bail!("The federation has no gateway"),
+        }
+    }
+
+    Ok(gateways.into_iter().map(|gateway| gateway.info).collect())
+}
+
+#[cfg(test)]
+mod tests;
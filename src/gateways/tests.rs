@@ -0,0 +1,37 @@
+use clap::Parser;
+
+use super::*;
+
+fn config(max_fee: u64, max_fee_percent: f64) -> Config {
+    Config::parse_from([
+        "pnyxtr",
+        "--data-dir",
+        "data",
+        "--max-fee",
+        &max_fee.to_string(),
+        "--max-fee-percent",
+        &max_fee_percent.to_string(),
+    ])
+}
+
+#[test]
+fn absolute_cap_for_small_payments() {
+    // 1% of 100sats is 1sat, below the 10sats cap
+    assert_eq!(max_fee_msats(&config(10, 1.0), 100_000), 10_000);
+}
+
+#[test]
+fn percentage_cap_for_large_payments() {
+    // 1% of 100ksats is 1ksat, above the 10sats cap
+    assert_eq!(max_fee_msats(&config(10, 1.0), 100_000_000), 1_000_000);
+}
+
+#[test]
+fn fractional_percentage() {
+    assert_eq!(max_fee_msats(&config(0, 0.5), 1_000_000), 5_000);
+}
+
+#[test]
+fn no_fees_allowed() {
+    assert_eq!(max_fee_msats(&config(0, 0.0), 1_000_000), 0);
+}
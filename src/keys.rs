@@ -13,8 +13,6 @@ use serde::{Deserialize, Serialize};
 pub struct Nip47Keys {
     pub server_key: SecretKey,
     pub user_key: SecretKey,
-    #[serde(default)]
-    pub sent_info: bool,
 }
 
 impl Nip47Keys {
@@ -28,7 +26,6 @@ impl Nip47Keys {
                 .context("Failed to convert to nostr_sdk::SecretKey")?,
             user_key: nostr_sdk::SecretKey::from_slice(user_key.secret_key()?.as_ref())
                 .context("Failed to convert to nostr_sdk::SecretKey")?,
-            sent_info: false,
         })
     }
 
@@ -37,11 +34,6 @@ impl Nip47Keys {
         Keys::new(self.server_key.clone().into())
     }
 
-    /// Returns a new `Keys` instance using the user key.
-    pub fn user_keys(&self) -> Keys {
-        Keys::new(self.user_key.clone().into())
-    }
-
     /// Retrieves keys from a file or generates and writes new keys if the file
     /// does not exist.
     pub fn load_or_generate_keys(keys_file: PathBuf) -> Result<Nip47Keys, anyhow::Error> {
@@ -71,10 +63,4 @@ impl Nip47Keys {
 
         Ok(keys)
     }
-
-    /// Writes the keys to the specified path.
-    pub fn write_keys(&self, path: &PathBuf) -> Result<Nip47Keys, anyhow::Error> {
-        let keys = self.clone();
-        Nip47Keys::save_keys(keys, path)
-    }
 }
@@ -1,65 +1,281 @@
 #![allow(clippy::too_many_arguments)]
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
+use anyhow::anyhow;
 use clap::Parser;
+use fedimint_client::ClientHandleArc;
+use fedimint_core::api::InviteCode;
+use fedimint_core::config::FederationId;
 use keys::Nip47Keys;
 use log::{debug, error, info};
 use multimint::MultiMint;
-use nostr::nips::nip04;
 use nostr::nips::nip47::*;
-use nostr::{Event, EventBuilder, EventId, Filter, JsonUtil, Kind, Timestamp};
-use nostr_sdk::{Client, RelayPoolNotification};
+use nostr::{Event, EventBuilder, EventId, Filter, JsonUtil, Kind, PublicKey, Timestamp};
+use nostr_sdk::{Client, RelayPoolNotification, RelayStatus};
+use qrcode::render::unicode;
+use qrcode::QrCode;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::{oneshot, Mutex};
+use tokio::task::spawn_blocking;
 use tokio::{select, spawn};
 
-use crate::config::Config;
+use crate::config::{Command, Config};
+use crate::connections::{Connection, Connections};
+use crate::encryption::Encryption;
+use crate::nwc::NwcError;
+use crate::payments::PaymentTracker;
+use crate::requests::RequestStore;
+use crate::settlements::Settlements;
 
 mod config;
+mod connections;
+mod encryption;
+mod gateways;
 mod keys;
+mod notifications;
 mod nwc;
 mod payments;
+mod requests;
+mod settlements;
+#[cfg(test)]
+mod test_utils;
 
 struct AppState {
     keys: Nip47Keys,
+    connections: Connections,
     multimint_client: MultiMint,
     nostr_client: Client,
     active_requests: HashSet<EventId>,
+    requests: Arc<RequestStore>,
+    /// When we last received a new request, by our clock, subscriptions
+    /// resume from a little before it after reconnecting
+    last_request_at: Timestamp,
+    payment_trackers: HashMap<PublicKey, Arc<Mutex<PaymentTracker>>>,
+    settlements: Arc<Mutex<Settlements>>,
     config: Config,
 }
 
-const METHODS: [Method; 8] = [
+impl AppState {
+    /// Returns the client of the federation the connection is bound to, or
+    /// of the default federation. Without either the only joined federation
+    /// is used.
+    async fn fedimint_client(&self, connection: &Connection) -> anyhow::Result<ClientHandleArc> {
+        let federation_id = connection
+            .federation_id
+            .as_ref()
+            .or(self.config.federation_id.as_ref());
+
+        match federation_id {
+            Some(federation_id) => self.federation_client(federation_id).await,
+            None => {
+                let mut clients = self.multimint_client.all().await;
+                match clients.len() {
+                    0 => Err(anyhow!(
+                        "No federation joined, pass --invite-code to join one"
+                    )),
+                    1 => Ok(clients.remove(0)),
+                    _ => Err(anyhow!(
+                        "More than one federation joined, pass --federation-id to pick the default"
+                    )),
+                }
+            }
+        }
+    }
+
+    /// Returns whether several connections share the wallet, in which case
+    /// each only sees its own transactions.
+    fn shared_wallet(&self) -> bool {
+        self.connections.iter().count() > 1
+    }
+
+    /// Returns the clients of every federation the connection may pay from,
+    /// its own federation first and then the ones it may also spend from, in
+    /// order of preference.
+    async fn fedimint_clients(
+        &self,
+        connection: &Connection,
+    ) -> anyhow::Result<Vec<ClientHandleArc>> {
+        let mut clients = vec![self.fedimint_client(connection).await?];
+        for federation_id in &connection.spend_federations {
+            let client = self.federation_client(federation_id).await?;
+            if clients
+                .iter()
+                .all(|c| c.federation_id() != client.federation_id())
+            {
+                clients.push(client);
+            }
+        }
+
+        Ok(clients)
+    }
+
+    /// Returns the client of a joined federation.
+    async fn federation_client(&self, federation_id: &str) -> anyhow::Result<ClientHandleArc> {
+        let federation_id = FederationId::from_str(federation_id)
+            .map_err(|e| anyhow!("Invalid federation id {federation_id}: {e}"))?;
+        self.multimint_client
+            .get(&federation_id)
+            .await
+            .ok_or(anyhow!(
+                "Federation {federation_id} not joined, pass its --invite-code to join it"
+            ))
+    }
+
+    /// Returns the payment tracker for the connection's budget, loading its
+    /// payments from disk the first time it is used.
+    fn payment_tracker(
+        &mut self,
+        connection: &Connection,
+    ) -> anyhow::Result<Arc<Mutex<PaymentTracker>>> {
+        let public_key = connection.public_key();
+        if let Some(tracker) = self.payment_trackers.get(&public_key) {
+            return Ok(tracker.clone());
+        }
+
+        let path = PathBuf::from(&self.config.payments_dir).join(format!("{public_key}.json"));
+        let tracker = Arc::new(Mutex::new(PaymentTracker::load(
+            path,
+            connection.budget_period(),
+        )?));
+        self.payment_trackers.insert(public_key, tracker.clone());
+        Ok(tracker)
+    }
+}
+
+/// Methods advertised to clients. `pay_keysend` and `multi_pay_keysend` are
+/// left out since federation gateways can't make spontaneous payments.
+const METHODS: [Method; 7] = [
     Method::GetInfo,
     Method::MakeInvoice,
     Method::GetBalance,
     Method::LookupInvoice,
+    Method::ListTransactions,
     Method::PayInvoice,
     Method::MultiPayInvoice,
-    Method::PayKeysend,
-    Method::MultiPayKeysend,
 ];
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     pretty_env_logger::try_init()?;
-    let config: Config = Config::parse();
+    let mut config: Config = Config::parse();
+    config.load_relays_file()?;
     let keys = keys::Nip47Keys::load_or_generate_keys(PathBuf::from(&config.keys_file))?;
+    let mut connections =
+        Connections::load_or_default(PathBuf::from(&config.connections_file), &keys, &config)?;
+
+    match &config.command {
+        Some(Command::AddConnection {
+            name,
+            max_amount,
+            budget,
+            budget_renewal,
+            calendar_budget,
+            permissions,
+            federation_id,
+            spend_federations,
+        }) => {
+            // the federation database is locked while pnyxtr runs, so whether
+            // the federations are joined is only checked on startup
+            for id in federation_id.iter().chain(spend_federations) {
+                FederationId::from_str(id)
+                    .map_err(|e| anyhow!("Invalid federation id {id}: {e}"))?;
+            }
+
+            let connection = Connection::new(
+                name.clone(),
+                max_amount.unwrap_or(config.max_amount),
+                budget.unwrap_or(config.budget),
+                budget_renewal.unwrap_or(config.budget_renewal),
+                *calendar_budget || config.calendar_budget,
+                permissions.methods(),
+                federation_id.clone(),
+                spend_federations.clone(),
+            )?;
+            connections.add(connection.clone())?;
+            connections.write_connections(&PathBuf::from(&config.connections_file))?;
+            // only hand out the uri once the connection is saved
+            print_connection_uri(&connection, &keys, &config, false)?;
+            return Ok(());
+        }
+        Some(Command::Uri { name }) => {
+            let connection = connections
+                .get_by_name(name)
+                .ok_or(anyhow!("Unknown connection: {name}"))?;
+            print_connection_uri(connection, &keys, &config, false)?;
+            return Ok(());
+        }
+        None => {}
+    }
+
+    for connection in connections.iter() {
+        print_connection_uri(connection, &keys, &config, config.hide_secret)?;
+    }
 
-    let multimint_client = MultiMint::new(PathBuf::from(&config.data_dir)).await?;
+    let mut multimint_client = MultiMint::new(PathBuf::from(&config.data_dir)).await?;
+    if let Some(invite_code) = &config.invite_code {
+        let invite_code = InviteCode::from_str(invite_code)?;
+        if !multimint_client.has(&invite_code.federation_id()).await {
+            let federation_id = multimint_client.register_new(invite_code, None).await?;
+            info!("Joined federation {federation_id}");
+        }
+    }
+    for client in multimint_client.all().await {
+        gateways::watch_gateways(client);
+    }
+    // the joined federations aren't kept in any order, so with more than one
+    // the default can't be picked for unbound connections
+    if config.federation_id.is_none()
+        && multimint_client.all().await.len() > 1
+        && connections
+            .iter()
+            .any(|connection| connection.federation_id.is_none())
+    {
+        return Err(anyhow!(
+            "More than one federation joined, pass --federation-id for connections not bound to one"
+        ));
+    }
+    // only joined federations can be paid from
+    for connection in connections.iter() {
+        let ids = connection
+            .federation_id
+            .iter()
+            .chain(&connection.spend_federations);
+        for id in ids {
+            let federation_id = FederationId::from_str(id)
+                .map_err(|e| anyhow!("Invalid federation id {id}: {e}"))?;
+            if !multimint_client.has(&federation_id).await {
+                return Err(anyhow!(
+                    "Federation {id} of connection {} not joined, pass its --invite-code to join it",
+                    connection.name
+                ));
+            }
+        }
+    }
     let nostr_client = Client::new(&keys.server_keys());
+    let requests = RequestStore::new(PathBuf::from(&config.requests_dir))?;
+    prune_requests(&requests, config.max_request_age, &HashSet::new());
+    let settlements = Settlements::load(PathBuf::from(&config.settlements_file))?;
 
     let state = Arc::new(Mutex::new(AppState {
         keys,
+        connections,
         multimint_client,
         nostr_client,
         active_requests: HashSet::new(),
+        requests: Arc::new(requests),
+        last_request_at: Timestamp::now(),
+        payment_trackers: HashMap::new(),
+        settlements: Arc::new(Mutex::new(settlements)),
         config,
     }));
 
+    notifications::watch_pending_receives(state.clone()).await;
+
     // Set up a oneshot channel to handle shutdown signal
     let (tx, rx) = oneshot::channel();
 
@@ -116,132 +332,360 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Prints the wallet connect uri of the connection to the console, and as a
+/// QR code if requested.
+fn print_connection_uri(
+    connection: &Connection,
+    keys: &Nip47Keys,
+    config: &Config,
+    hide_secret: bool,
+) -> anyhow::Result<()> {
+    let server_key = keys.server_keys().public_key();
+
+    if hide_secret {
+        let uri = connection.redacted_uri(server_key, &config.relays)?;
+        println!("Connection {}: {uri}", connection.name);
+        return Ok(());
+    }
+
+    let uri = connection.uri(server_key, &config.relays)?;
+    println!("Connection {}: {uri}", connection.name);
+    if config.qr {
+        let qr = QrCode::new(uri.to_string().as_bytes())?
+            .render::<unicode::Dense1x2>()
+            .dark_color(unicode::Dense1x2::Light)
+            .light_color(unicode::Dense1x2::Dark)
+            .build();
+        println!("{qr}");
+    }
+
+    Ok(())
+}
+
+/// How often the relay connections are checked.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Consecutive health checks without a connected relay before reconnecting.
+const MAX_FAILED_HEALTH_CHECKS: u32 = 3;
+/// How far before the last request received subscriptions resume, for
+/// requests dated a little earlier than we received them.
+const RESUME_OVERLAP: u64 = 60;
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+
 async fn event_loop(state: Arc<Mutex<AppState>>) -> anyhow::Result<()> {
+    let mut reconnect_delay = MIN_RECONNECT_DELAY;
+
     // loop in case we get disconnected
     loop {
-        let state_clone = state.clone();
-        let keys = &state_clone.lock().await.keys;
-        let client = Client::new(&keys.server_keys());
-        client
-            .add_relay(state.lock().await.config.relay.as_str())
-            .await?;
-
-        client.connect().await;
-
-        // broadcast info event
-        if !keys.sent_info {
-            let content: String = METHODS
-                .iter()
-                .map(|i| i.to_string())
-                .collect::<Vec<_>>()
-                .join(" ");
-            let info = EventBuilder::new(Kind::WalletConnectInfo, content, [])
-                .to_event(&keys.server_keys())?;
-            client.send_event(info).await?;
-
-            state.lock().await.keys.sent_info = true;
-            keys.write_keys(&PathBuf::from(&state.lock().await.config.keys_file));
-        }
-
-        let subscription = Filter::new()
-            .kinds(vec![Kind::WalletConnectRequest])
-            .author(keys.user_keys().public_key())
-            .pubkey(keys.server_keys().public_key())
-            .since(Timestamp::now());
-
-        client.subscribe(vec![subscription], None).await;
-
-        info!("Listening for nip 47 requests...");
-
-        let (tx, mut rx) = tokio::sync::watch::channel(());
-        spawn(async move {
-            tokio::time::sleep(Duration::from_secs(60 * 15)).await;
-            tx.send_modify(|_| ())
-        });
-
-        let mut notifications = client.notifications();
-        loop {
-            select! {
-                Ok(notification) = notifications.recv() => {
-                    match notification {
-                        RelayPoolNotification::Event { event, .. } => {
-                            if event.kind == Kind::WalletConnectRequest
-                                && event.pubkey == keys.user_keys().public_key()
-                                && event.verify().is_ok()
-                            {
-                                debug!("Received event!");
-                                spawn(async move {
-                                    let event_id = event.id;
-                                    state.lock().await.active_requests.insert(event_id);
-
-                                    match tokio::time::timeout(
-                                        Duration::from_secs(60),
-                                        handle_nwc_request(*event, state.clone()),
-                                    )
-                                    .await
-                                    {
-                                        Ok(Ok(_)) => {},
-                                        Ok(Err(e)) => error!("Error processing request: {e}"),
-                                        Err(e) => error!("Timeout error: {e}"),
-                                    }
-
-                                    // remove request from active requests
-                                    state.lock().await.active_requests.remove(&event_id);
-                                });
-                            } else {
-                                error!("Invalid event: {}", event.as_json());
-                            }
+        match relay_session(state.clone()).await {
+            // the relays were reachable, so start backing off from scratch
+            Ok(true) => reconnect_delay = MIN_RECONNECT_DELAY,
+            Ok(false) => {}
+            Err(e) => error!("Relay connection error: {e}"),
+        }
+
+        info!("Reconnecting in {}s...", reconnect_delay.as_secs());
+        tokio::time::sleep(reconnect_delay).await;
+        reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Connects to the relays and handles requests until no relay is reachable
+/// anymore. Returns whether any relay was connected during the session.
+async fn relay_session(state: Arc<Mutex<AppState>>) -> anyhow::Result<bool> {
+    let keys = state.lock().await.keys.clone();
+    let client = Client::new(&keys.server_keys());
+    let relays = state.lock().await.config.relays.clone();
+    for relay in relays {
+        client.add_relay(relay.as_str()).await?;
+    }
+
+    client.connect().await;
+    state.lock().await.nostr_client = client.clone();
+
+    // broadcast info event, it's replaceable so it is kept up to date
+    // with the supported methods, encryption schemes and notifications
+    let content: String = METHODS
+        .iter()
+        .map(|i| i.to_string())
+        .chain(["notifications".to_string()])
+        .collect::<Vec<_>>()
+        .join(" ");
+    let tags = [Encryption::info_tag(), notifications::info_tag()];
+    let info =
+        EventBuilder::new(Kind::WalletConnectInfo, content, tags).to_event(&keys.server_keys())?;
+    if let Err(e) = client.send_event(info).await {
+        error!("Error broadcasting info event: {e}");
+    }
+
+    // resubscribe from the last request we handled so requests sent while
+    // we were disconnected aren't lost, duplicates are filtered out below.
+    // Requests of every app are received, so unknown apps are answered.
+    // Requests too old to be executed aren't fetched again, their records may
+    // be pruned already so they couldn't be told apart from new ones.
+    let since = {
+        let state = state.lock().await;
+        let mut since = state
+            .last_request_at
+            .as_u64()
+            .saturating_sub(RESUME_OVERLAP);
+        if state.config.max_request_age > 0 {
+            let oldest = Timestamp::now()
+                .as_u64()
+                .saturating_sub(state.config.max_request_age);
+            since = since.max(oldest);
+        }
+        Timestamp::from(since)
+    };
+    let subscription = Filter::new()
+        .kinds(vec![Kind::WalletConnectRequest])
+        .pubkey(keys.server_keys().public_key())
+        .since(since);
+
+    client.subscribe(vec![subscription], None).await;
+
+    info!("Listening for nip 47 requests...");
+
+    let mut health_check = tokio::time::interval_at(
+        tokio::time::Instant::now() + HEALTH_CHECK_INTERVAL,
+        HEALTH_CHECK_INTERVAL,
+    );
+    let mut failed_health_checks = 0;
+    let mut was_connected = false;
+
+    let mut notifications = client.notifications();
+    loop {
+        select! {
+            Ok(notification) = notifications.recv() => {
+                match notification {
+                    RelayPoolNotification::Event { event, .. } => {
+                        if event.kind != Kind::WalletConnectRequest || event.verify().is_err() {
+                            error!("Invalid event: {}", event.as_json());
+                            continue;
+                        }
+
+                        // requests of unknown apps are answered as unauthorized,
+                        // there is nothing to execute or record for them
+                        if state.lock().await.connections.get(&event.pubkey).is_none() {
+                            debug!("Received request from unknown app {}", event.pubkey);
+                            let state = state.clone();
+                            spawn(async move {
+                                if let Err(e) = handle_nwc_request(*event, state).await {
+                                    error!("Error processing request: {e}");
+                                }
+                            });
+                            continue;
                         }
-                        RelayPoolNotification::Shutdown => {
-                            info!("Relay pool shutdown");
-                            break;
+
+                        // the same request arrives from every relay it was sent to,
+                        // and relays may deliver it again later on. The record is
+                        // synced to disk, so that is done without holding the state.
+                        let requests = state.lock().await.requests.clone();
+                        let begun = spawn_blocking({
+                            let requests = requests.clone();
+                            let event = event.clone();
+                            move || requests.begin(&event)
+                        })
+                        .await
+                        .unwrap_or_else(|e| Err(anyhow!("Recording task failed: {e}")));
+                        match begun {
+                            Ok(true) => {}
+                            Ok(false) => {
+                                debug!("Ignoring duplicate request {}", event.id);
+                                resend_responses(requests, client.clone(), event.id);
+                                continue;
+                            }
+                            Err(e) => {
+                                error!("Error recording request {}: {e}", event.id);
+                                continue;
+                            }
                         }
-                        _ => {}
+                        // the app's clock may be off, so go by ours
+                        state.lock().await.last_request_at = Timestamp::now();
+
+                        debug!("Received event!");
+                        let state = state.clone();
+                        spawn(async move {
+                            let event_id = event.id;
+                            state.lock().await.active_requests.insert(event_id);
+
+                            // handlers time out on their own, payments
+                            // must not be dropped mid-flight
+                            if let Err(e) = handle_nwc_request(*event, state.clone()).await {
+                                error!("Error processing request: {e}");
+                            }
+
+                            // remove request from active requests
+                            state.lock().await.active_requests.remove(&event_id);
+                        });
+                    }
+                    RelayPoolNotification::Shutdown => {
+                        info!("Relay pool shutdown");
+                        break;
                     }
+                    _ => {}
                 }
-                _ = rx.changed() => {
-                    break;
+            }
+            _ = health_check.tick() => {
+                // pruning reads every record, so it runs off the runtime without
+                // holding the state or the event loop
+                let (requests, max_request_age, active_requests) = {
+                    let state = state.lock().await;
+                    (
+                        state.requests.clone(),
+                        state.config.max_request_age,
+                        state.active_requests.clone(),
+                    )
+                };
+                spawn_blocking(move || prune_requests(&requests, max_request_age, &active_requests));
+
+                let mut connected = false;
+                for relay in client.relays().await.values() {
+                    if relay.status().await == RelayStatus::Connected {
+                        connected = true;
+                        break;
+                    }
+                }
+
+                if connected {
+                    was_connected = true;
+                    failed_health_checks = 0;
+                } else {
+                    failed_health_checks += 1;
+                    error!("No relay connected ({failed_health_checks}/{MAX_FAILED_HEALTH_CHECKS})");
+                    if failed_health_checks >= MAX_FAILED_HEALTH_CHECKS {
+                        break;
+                    }
                 }
             }
         }
+    }
+
+    client.disconnect().await?;
 
-        client.disconnect().await?;
+    Ok(was_connected)
+}
+
+/// Sends the responses of an already handled request again, without
+/// executing it a second time.
+fn resend_responses(requests: Arc<RequestStore>, client: Client, request_id: EventId) {
+    spawn(async move {
+        let responses = spawn_blocking(move || requests.responses(&request_id))
+            .await
+            .unwrap_or_else(|e| Err(anyhow!("Reading task failed: {e}")));
+        let responses = match responses {
+            Ok(responses) => responses,
+            Err(e) => {
+                error!("Error reading responses of request {request_id}: {e}");
+                return;
+            }
+        };
+
+        for response in responses {
+            if let Err(e) = client.send_event(response).await {
+                error!("Error resending response: {e}");
+            }
+        }
+    });
+}
+
+/// Removes the records of requests too old to be executed anymore. Without a
+/// max request age old requests are still executed, so every record is kept.
+fn prune_requests(
+    requests: &RequestStore,
+    max_request_age: u64,
+    active_requests: &HashSet<EventId>,
+) {
+    if max_request_age == 0 {
+        return;
+    }
+
+    match requests.prune(Duration::from_secs(max_request_age), active_requests) {
+        Ok(0) => {}
+        Ok(pruned) => debug!("Pruned {pruned} old request records"),
+        Err(e) => error!("Error pruning request records: {e}"),
     }
 }
 
 async fn handle_nwc_request(event: Event, state: Arc<Mutex<AppState>>) -> anyhow::Result<()> {
-    let keys = state.lock().await.keys.clone();
-    let server_keys = keys.server_keys();
-    let secret_key = server_keys.secret_key()?;
-    let decrypted = nip04::decrypt(&secret_key, &keys.user_keys().public_key(), &event.content)?;
-    let req: Request = Request::from_json(&decrypted)?;
+    let encryption = match Encryption::from_event(&event) {
+        Ok(encryption) => encryption,
+        Err(e) => {
+            // answer in the scheme every app understands
+            let error = NwcError::NotImplemented(e.to_string());
+            return nwc::send_error(error, None, &event, Encryption::Nip04, &state).await;
+        }
+    };
+
+    let (keys, connection) = {
+        let state = state.lock().await;
+        (
+            state.keys.clone(),
+            state.connections.get(&event.pubkey).cloned(),
+        )
+    };
+    let Some(connection) = connection else {
+        let error = NwcError::Unauthorized(format!("Unknown connection: {}", event.pubkey));
+        return nwc::send_error(error, None, &event, encryption, &state).await;
+    };
+
+    let secret_key = keys.server_keys().secret_key()?;
+    let decrypted = match encryption.decrypt(&secret_key, &event.pubkey, &event.content) {
+        Ok(decrypted) => decrypted,
+        Err(e) => {
+            let error = NwcError::InvalidRequest(format!("Could not decrypt request: {e}"));
+            return nwc::send_error(error, None, &event, encryption, &state).await;
+        }
+    };
+    let req = match Request::from_json(&decrypted) {
+        Ok(req) => req,
+        Err(e) => {
+            // answer for the method the app asked for, even one we don't know
+            let method = serde_json::from_str::<serde_json::Value>(&decrypted)
+                .ok()
+                .and_then(|json| json.get("method")?.as_str().map(str::to_string));
+            let error = match &method {
+                Some(method) if Method::from_str(method).is_err() => {
+                    NwcError::NotImplemented(format!("Method not supported: {method}"))
+                }
+                _ => NwcError::InvalidRequest(format!("Invalid request: {e}")),
+            };
+            return nwc::send_error(error, method, &event, encryption, &state).await;
+        }
+    };
 
-    debug!("Request params: {:?}", req.params);
+    debug!("Request from {}: {:?}", connection.name, req.params);
 
     // split up the multis into their parts
     match req.params {
         RequestParams::MultiPayInvoice(params) => {
-            for inv in params.invoices {
-                let params = RequestParams::PayInvoice(inv);
-                let event = event.clone();
-                let state = state.clone();
-                spawn(async move { nwc::handle_nwc(params, req.method, &event, state).await })
-                    .await??;
-            }
-
-            Ok(())
+            let items = params
+                .invoices
+                .into_iter()
+                .map(RequestParams::PayInvoice)
+                .collect();
+            nwc::handle_nwc_multi(items, req.method, &event, &connection, encryption, state).await
         }
         RequestParams::MultiPayKeysend(params) => {
-            for inv in params.keysends {
-                let params = RequestParams::PayKeysend(inv);
-                let event = event.clone();
-                let state = state.clone();
-                spawn(async move { nwc::handle_nwc(params, req.method, &event, state).await })
-                    .await??;
-            }
-
-            Ok(())
+            let items = params
+                .keysends
+                .into_iter()
+                .map(RequestParams::PayKeysend)
+                .collect();
+            nwc::handle_nwc_multi(items, req.method, &event, &connection, encryption, state).await
+        }
+        params => {
+            nwc::handle_nwc(
+                params,
+                req.method,
+                &event,
+                &connection,
+                encryption,
+                state,
+                None,
+                None,
+            )
+            .await
         }
-        params => nwc::handle_nwc(params, req.method, &event, state).await,
     }
 }
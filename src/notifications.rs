@@ -0,0 +1,259 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::anyhow;
+use fedimint_client::ClientHandleArc;
+use fedimint_core::core::OperationId;
+use fedimint_ln_client::{
+    LightningClientModule, LightningOperationMeta, LightningOperationMetaVariant, LnReceiveState,
+};
+use fedimint_mint_client::{
+    MintClientModule, MintOperationMeta, MintOperationMetaVariant, ReissueExternalNotesState,
+};
+use futures_util::StreamExt;
+use log::{debug, error};
+use nostr::nips::nip47::TransactionType;
+use nostr::{EventBuilder, Kind, Tag, TagKind};
+use nostr_sdk::{Client, RelayStatus};
+use serde_json::json;
+use tokio::spawn;
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+
+use crate::encryption::Encryption;
+use crate::nwc::{operation_visible, transaction_from_operation, OperationPager};
+use crate::AppState;
+
+/// Kind of notification events encrypted with NIP-04.
+const NIP04_NOTIFICATION_KIND: u64 = 23196;
+/// Kind of notification events encrypted with NIP-44.
+const NIP44_NOTIFICATION_KIND: u64 = 23197;
+
+/// Notification types we publish.
+const NOTIFICATION_TYPES: [&str; 2] = ["payment_received", "payment_sent"];
+
+/// How often we check for a connected relay while a notification waits for
+/// one.
+const CONNECT_RETRY_DELAY: Duration = Duration::from_secs(5);
+/// How many times we check for a connected relay before dropping a
+/// notification.
+const CONNECT_ATTEMPTS: u32 = 120;
+
+/// Returns the `notifications` tag advertising the notification types we
+/// publish.
+pub fn info_tag() -> Tag {
+    Tag::Generic(
+        TagKind::Custom("notifications".to_string()),
+        vec![NOTIFICATION_TYPES.join(" ")],
+    )
+}
+
+/// Waits for an invoice we created to be paid and notifies the connection
+/// that created it.
+pub fn watch_receive(
+    state: Arc<Mutex<AppState>>,
+    client: ClientHandleArc,
+    operation_id: OperationId,
+    created_at: SystemTime,
+) {
+    spawn(async move {
+        let lightning_module = client.get_first_module::<LightningClientModule>();
+        let mut updates = match lightning_module.subscribe_ln_receive(operation_id).await {
+            Ok(updates) => updates.into_stream(),
+            Err(e) => {
+                error!("Error watching invoice: {e}");
+                return;
+            }
+        };
+
+        let mut claimed = false;
+        while let Some(update) = updates.next().await {
+            claimed = matches!(update, LnReceiveState::Claimed);
+        }
+
+        if claimed {
+            notify_operation(state, client.clone(), operation_id, created_at);
+        }
+    });
+}
+
+/// Waits for ecash being reissued into the wallet and notifies the
+/// connections that see it once it is ours. Only reissues pending at
+/// startup are watched, nothing starts them while we run and the operation
+/// log can't be subscribed to.
+pub fn watch_reissue(
+    state: Arc<Mutex<AppState>>,
+    client: ClientHandleArc,
+    operation_id: OperationId,
+    created_at: SystemTime,
+) {
+    spawn(async move {
+        let mint_module = client.get_first_module::<MintClientModule>();
+        let mut updates = match mint_module
+            .subscribe_reissue_external_notes(operation_id)
+            .await
+        {
+            Ok(updates) => updates.into_stream(),
+            Err(e) => {
+                error!("Error watching ecash reissue: {e}");
+                return;
+            }
+        };
+
+        let mut done = false;
+        while let Some(update) = updates.next().await {
+            done = matches!(update, ReissueExternalNotesState::Done);
+        }
+
+        if done {
+            notify_operation(state, client.clone(), operation_id, created_at);
+        }
+    });
+}
+
+/// Watches the unexpired invoices and the ecash reissues that were still
+/// pending when pnyxtr last shut down.
+pub async fn watch_pending_receives(state: Arc<Mutex<AppState>>) {
+    let clients = state.lock().await.multimint_client.all().await;
+    for client in clients {
+        let mut operations = OperationPager::new(client.clone());
+        while let Some((key, entry)) = operations.next_operation().await {
+            let kind = entry.operation_module_kind();
+            if kind == fedimint_ln_common::KIND.as_str() {
+                let meta: LightningOperationMeta = entry.meta();
+                // expired invoices can't be paid anymore, and their updates
+                // may never end
+                if let LightningOperationMetaVariant::Receive { invoice, .. } = meta.variant {
+                    if !invoice.is_expired() && entry.outcome::<LnReceiveState>().is_none() {
+                        watch_receive(
+                            state.clone(),
+                            client.clone(),
+                            key.operation_id,
+                            key.creation_time,
+                        );
+                    }
+                }
+            } else if kind == fedimint_mint_client::KIND.as_str() {
+                let meta: MintOperationMeta = entry.meta();
+                if matches!(meta.variant, MintOperationMetaVariant::Reissuance { .. })
+                    && entry.outcome::<ReissueExternalNotesState>().is_none()
+                {
+                    watch_reissue(
+                        state.clone(),
+                        client.clone(),
+                        key.operation_id,
+                        key.creation_time,
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Records that an operation settled and publishes a notification for it to
+/// the connections that see it in their transactions. `created_at` is when
+/// the operation was started.
+pub fn notify_operation(
+    state: Arc<Mutex<AppState>>,
+    client: ClientHandleArc,
+    operation_id: OperationId,
+    created_at: SystemTime,
+) {
+    spawn(async move {
+        let settlements = state.lock().await.settlements.clone();
+        if let Err(e) = settlements.lock().await.record(&operation_id) {
+            error!("Error recording settlement: {e}");
+        }
+
+        if let Err(e) = send_notification(state, client, operation_id, created_at).await {
+            error!("Error sending notification: {e}");
+        }
+    });
+}
+
+async fn send_notification(
+    state: Arc<Mutex<AppState>>,
+    client: ClientHandleArc,
+    operation_id: OperationId,
+    created_at: SystemTime,
+) -> anyhow::Result<()> {
+    let Some(entry) = client.operation_log().get_operation(operation_id).await else {
+        return Ok(());
+    };
+    let settlements = state.lock().await.settlements.clone();
+    let settled_at = settlements.lock().await.settled_at(&operation_id);
+    let Some((transaction, _)) =
+        transaction_from_operation(operation_id, created_at, settled_at, &entry)
+    else {
+        return Ok(());
+    };
+
+    let notification_type = match transaction.transaction_type {
+        Some(TransactionType::Incoming) => "payment_received",
+        _ => "payment_sent",
+    };
+    let content = json!({
+        "notification_type": notification_type,
+        "notification": transaction,
+    })
+    .to_string();
+
+    let (keys, recipients) = {
+        let state = state.lock().await;
+        // the connections that would see the transaction when listing theirs
+        let shared_wallet = state.shared_wallet();
+        let mut recipients = Vec::new();
+        for connection in state.connections.iter() {
+            if !operation_visible(&entry, connection, shared_wallet) {
+                continue;
+            }
+            let Ok(connection_clients) = state.fedimint_clients(connection).await else {
+                continue;
+            };
+            if connection_clients.iter().any(|connection_client| {
+                connection_client.federation_id() == client.federation_id()
+            }) {
+                recipients.push(connection.public_key());
+            }
+        }
+        (state.keys.clone(), recipients)
+    };
+    let nostr_client = connected_client(&state).await?;
+    let server_keys = keys.server_keys();
+    let secret_key = server_keys.secret_key()?;
+
+    for public_key in recipients {
+        // we don't know which scheme the app supports, so notify with both
+        for (kind, encryption) in [
+            (NIP04_NOTIFICATION_KIND, Encryption::Nip04),
+            (NIP44_NOTIFICATION_KIND, Encryption::Nip44V2),
+        ] {
+            let encrypted = encryption.encrypt(&secret_key, &public_key, content.clone())?;
+            let event =
+                EventBuilder::new(Kind::from(kind), encrypted, [Tag::public_key(public_key)])
+                    .to_event(&server_keys)?;
+            nostr_client.send_event(event).await?;
+        }
+
+        debug!("Sent {notification_type} notification to {public_key}");
+    }
+
+    Ok(())
+}
+
+/// Returns the current relay client once a relay is connected. Operations may
+/// finish before the first relay session connects or while reconnecting, so
+/// their notifications wait for one.
+async fn connected_client(state: &Arc<Mutex<AppState>>) -> anyhow::Result<Client> {
+    for _ in 0..CONNECT_ATTEMPTS {
+        let client = state.lock().await.nostr_client.clone();
+        for relay in client.relays().await.values() {
+            if relay.status().await == RelayStatus::Connected {
+                return Ok(client);
+            }
+        }
+        sleep(CONNECT_RETRY_DELAY).await;
+    }
+
+    Err(anyhow!("No relay connected to send the notification"))
+}
@@ -0,0 +1,82 @@
+use std::fmt;
+
+use nostr::nips::nip47::{ErrorCode, Method, NIP47Error, Response};
+
+/// Reasons a request fails, each answered with its NIP-47 error code.
+#[derive(Debug)]
+pub enum NwcError {
+    /// The request or its parameters could not be parsed
+    InvalidRequest(String),
+    /// The method is not supported
+    NotImplemented(String),
+    /// The wallet doesn't hold enough ecash for the payment
+    InsufficientBalance(String),
+    /// The gateway or federation failed to make the payment
+    PaymentFailed(String),
+    /// The invoice or the request itself expired before it was executed
+    Expired(String),
+    /// The payment would exceed the connection's limits
+    QuotaExceeded(String),
+    /// The connection is unknown
+    Unauthorized(String),
+    /// The connection isn't allowed to call the method
+    Restricted(String),
+    NotFound(String),
+    Internal(anyhow::Error),
+}
+
+impl NwcError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            // NIP-47 has no code for these, so they are reported as other
+            NwcError::InvalidRequest(_) | NwcError::Expired(_) => ErrorCode::Other,
+            NwcError::NotImplemented(_) => ErrorCode::NotImplemented,
+            NwcError::InsufficientBalance(_) => ErrorCode::InsufficientBalance,
+            NwcError::PaymentFailed(_) => ErrorCode::PaymentFailed,
+            NwcError::QuotaExceeded(_) => ErrorCode::QuotaExceeded,
+            NwcError::Unauthorized(_) => ErrorCode::Unauthorized,
+            NwcError::Restricted(_) => ErrorCode::Restricted,
+            NwcError::NotFound(_) => ErrorCode::NotFound,
+            NwcError::Internal(_) => ErrorCode::Internal,
+        }
+    }
+
+    pub fn to_nip47(&self) -> NIP47Error {
+        NIP47Error {
+            code: self.code(),
+            message: self.to_string(),
+        }
+    }
+
+    /// Returns the error response to a request for `method`.
+    pub fn into_response(self, method: Method) -> Response {
+        Response {
+            result_type: method,
+            error: Some(self.to_nip47()),
+            result: None,
+        }
+    }
+}
+
+impl fmt::Display for NwcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NwcError::InvalidRequest(msg)
+            | NwcError::NotImplemented(msg)
+            | NwcError::InsufficientBalance(msg)
+            | NwcError::PaymentFailed(msg)
+            | NwcError::Expired(msg)
+            | NwcError::QuotaExceeded(msg)
+            | NwcError::Unauthorized(msg)
+            | NwcError::Restricted(msg)
+            | NwcError::NotFound(msg) => write!(f, "{msg}"),
+            NwcError::Internal(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<anyhow::Error> for NwcError {
+    fn from(e: anyhow::Error) -> Self {
+        NwcError::Internal(e)
+    }
+}
@@ -1,23 +1,45 @@
 use std::sync::Arc;
 
 use log::info;
-use nostr::nips::nip47::{Method, Response};
+use nostr::nips::nip47::{GetBalanceResponseResult, Method, Response, ResponseResult};
 use tokio::sync::Mutex;
 
+use super::NwcError;
+use crate::connections::Connection;
 use crate::AppState;
 
 pub async fn handle_nwc_get_balance(
+    connection: &Connection,
     state: Arc<Mutex<AppState>>,
     method: Method,
-) -> anyhow::Result<Response> {
-    let tracker = tracker.lock().await.sum_payments();
-    let remaining_msats = config.daily_limit * 1_000 - tracker;
-    info!("Current balance: {remaining_msats}msats");
-    Response {
-        result_type: Method::GetBalance,
+) -> Result<Response, NwcError> {
+    let (clients, tracker, config) = {
+        let mut state = state.lock().await;
+        (
+            state.fedimint_clients(connection).await?,
+            state.payment_tracker(connection)?,
+            state.config.clone(),
+        )
+    };
+
+    // the connection can spend from all of these federations
+    let mut balance_msats = 0;
+    for client in clients {
+        balance_msats += client.get_balance().await.msats;
+    }
+    if config.cap_balance_to_budget && connection.budget > 0 {
+        let spent_msats = tracker.lock().await.spent();
+        let remaining_msats = (connection.budget * 1_000).saturating_sub(spent_msats);
+        balance_msats = balance_msats.min(remaining_msats);
+    }
+
+    info!("Current balance: {balance_msats}msats");
+
+    Ok(Response {
+        result_type: method,
         error: None,
         result: Some(ResponseResult::GetBalance(GetBalanceResponseResult {
-            balance: remaining_msats,
+            balance: balance_msats,
         })),
-    }
+    })
 }
@@ -1,28 +1,64 @@
 use std::sync::Arc;
 
+use fedimint_core::bitcoin::Network;
+use fedimint_wallet_client::api::WalletFederationApi;
+use fedimint_wallet_client::WalletClientModule;
 use log::info;
-use nostr::nips::nip47::{Method, Response};
+use nostr::nips::nip47::{GetInfoResponseResult, Method, Response, ResponseResult};
 use tokio::sync::Mutex;
 
-use crate::AppState;
+use super::NwcError;
+use crate::connections::Connection;
+use crate::{AppState, METHODS};
 
 pub async fn handle_nwc_get_info(
+    connection: &Connection,
     state: Arc<Mutex<AppState>>,
     method: Method,
-) -> anyhow::Result<Response> {
-    let lnd_info: GetInfoResponse = lnd.get_info(GetInfoRequest {}).await?.into_inner();
-    info!("Getting info");
-    Response {
-        result_type: Method::GetBalance,
+) -> Result<Response, NwcError> {
+    let (client, keys) = {
+        let state = state.lock().await;
+        (state.fedimint_client(connection).await?, state.keys.clone())
+    };
+
+    let federation_id = client.federation_id();
+    let alias = client
+        .get_config()
+        .global
+        .federation_name()
+        .map(|name| name.to_string())
+        .unwrap_or_else(|| federation_id.to_string());
+
+    let wallet_module = client.get_first_module::<WalletClientModule>();
+    let network = match wallet_module.get_network() {
+        Network::Bitcoin => "mainnet".to_string(),
+        network => network.to_string(),
+    };
+    let block_height = wallet_module
+        .api
+        .fetch_consensus_block_count()
+        .await
+        .map(|count| count.saturating_sub(1) as u32)
+        .unwrap_or_default();
+
+    info!("Getting info for federation {federation_id}");
+
+    Ok(Response {
+        result_type: method,
         error: None,
         result: Some(ResponseResult::GetInfo(GetInfoResponseResult {
-            alias: lnd_info.alias,
-            color: lnd_info.color,
-            pubkey: lnd_info.identity_pubkey,
-            network: "".to_string(),
-            block_height: lnd_info.block_height,
-            block_hash: lnd_info.block_hash,
-            methods: METHODS.iter().map(|i| i.to_string()).collect(),
+            alias,
+            color: "".to_string(),
+            pubkey: keys.server_keys().public_key().to_string(),
+            network,
+            block_height,
+            block_hash: "".to_string(),
+            // only the methods this connection may call
+            methods: METHODS
+                .iter()
+                .filter(|method| connection.allows(method))
+                .map(|method| method.to_string())
+                .collect(),
         })),
-    }
+    })
 }
@@ -0,0 +1,99 @@
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use log::info;
+use nostr::nips::nip47::{
+    ListTransactionsRequestParams, LookupInvoiceResponseResult, Method, Response, ResponseResult,
+};
+use tokio::sync::Mutex;
+
+use super::{operation_visible, transaction_from_operation, NwcError, OperationPager};
+use crate::connections::Connection;
+use crate::AppState;
+
+pub async fn handle_nwc_list_transactions(
+    params: ListTransactionsRequestParams,
+    connection: &Connection,
+    state: Arc<Mutex<AppState>>,
+    method: Method,
+) -> Result<Response, NwcError> {
+    let (clients, shared_wallet, settlements) = {
+        let state = state.lock().await;
+        (
+            state.fedimint_clients(connection).await?,
+            state.shared_wallet(),
+            state.settlements.clone(),
+        )
+    };
+    let offset = params.offset.unwrap_or(0) as usize;
+    let wanted = offset.saturating_add(params.limit.map_or(usize::MAX, |limit| limit as usize));
+
+    let mut pagers: Vec<_> = clients.into_iter().map(OperationPager::new).collect();
+    let mut transactions = Vec::new();
+    while transactions.len() < wanted {
+        // payments may come from several federations, list the newest first
+        // across all of them
+        let mut newest = None;
+        for (index, pager) in pagers.iter_mut().enumerate() {
+            if let Some(creation_time) = pager.peek_creation_time().await {
+                if newest.map_or(true, |(_, newest_time)| creation_time > newest_time) {
+                    newest = Some((index, creation_time));
+                }
+            }
+        }
+        let Some((index, _)) = newest else {
+            break;
+        };
+        let Some((key, entry)) = pagers[index].next_operation().await else {
+            break;
+        };
+
+        // every operation left is older still
+        let created_at = key
+            .creation_time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if params.from.map_or(false, |from| created_at < from) {
+            break;
+        }
+
+        if !operation_visible(&entry, connection, shared_wallet) {
+            continue;
+        }
+        let settled_at = settlements.lock().await.settled_at(&key.operation_id);
+        let transaction =
+            transaction_from_operation(key.operation_id, key.creation_time, settled_at, &entry);
+        if let Some((tx, settled)) = transaction {
+            if matches_params(&tx, settled, &params) {
+                transactions.push(tx);
+            }
+        }
+    }
+    let transactions: Vec<_> = transactions.into_iter().skip(offset).collect();
+
+    info!("Listed {} transactions", transactions.len());
+
+    Ok(Response {
+        result_type: method,
+        error: None,
+        result: Some(ResponseResult::ListTransactions(transactions)),
+    })
+}
+
+/// Returns whether a transaction is one of the ones asked for.
+fn matches_params(
+    tx: &LookupInvoiceResponseResult,
+    settled: bool,
+    params: &ListTransactionsRequestParams,
+) -> bool {
+    params.from.map_or(true, |from| tx.created_at >= from)
+        && params.until.map_or(true, |until| tx.created_at <= until)
+        && (params.unpaid.unwrap_or(false) || settled)
+        && params
+            .transaction_type
+            .as_ref()
+            .map_or(true, |transaction_type| {
+                tx.transaction_type.as_ref() == Some(transaction_type)
+            })
+}
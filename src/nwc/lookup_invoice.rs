@@ -1,76 +1,220 @@
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use fedimint_client::oplog::OperationLogEntry;
+use fedimint_core::core::OperationId;
+use fedimint_ln_client::{
+    InternalPayState, LightningOperationMeta, LightningOperationMetaVariant, LnPayState,
+    LnReceiveState,
+};
+use fedimint_mint_client::{
+    MintOperationMeta, MintOperationMetaVariant, ReissueExternalNotesState,
+};
+use lightning_invoice::{Bolt11Invoice, Bolt11InvoiceDescription};
 use log::info;
-use nostr::nips::nip47::{Method, RequestParams, Response};
+use nostr::nips::nip47::{
+    LookupInvoiceRequestParams, LookupInvoiceResponseResult, Method, Response, ResponseResult,
+    TransactionType,
+};
 use tokio::sync::Mutex;
 
+use super::{operation_visible, NwcError, OperationPager};
+use crate::connections::Connection;
 use crate::AppState;
 
-async fn handle_nwc_lookup_invoice(
-    params: RequestParams,
+pub async fn handle_nwc_lookup_invoice(
+    params: LookupInvoiceRequestParams,
+    connection: &Connection,
     state: Arc<Mutex<AppState>>,
     method: Method,
-) -> anyhow::Result<Response> {
-    let mut invoice: Option<Bolt11Invoice> = None;
-    let payment_hash: Vec<u8> = match params.payment_hash {
+) -> Result<Response, NwcError> {
+    let payment_hash = match params.payment_hash {
         None => match params.invoice {
-            None => return Err(anyhow!("Missing payment_hash or invoice")),
+            None => {
+                return Err(NwcError::InvalidRequest(
+                    "Missing payment_hash or invoice".to_string(),
+                ))
+            }
             Some(bolt11) => {
                 let inv = Bolt11Invoice::from_str(&bolt11)
-                    .map_err(|_| anyhow!("Failed to parse invoice"))?;
-                invoice = Some(inv.clone());
-                inv.payment_hash().into_32().to_vec()
+                    .map_err(|_| NwcError::InvalidRequest("Failed to parse invoice".to_string()))?;
+                inv.payment_hash().to_string()
             }
         },
-        Some(str) => FromHex::from_hex(&str)?,
+        Some(str) => str.to_lowercase(),
+    };
+
+    let (clients, shared_wallet, settlements) = {
+        let state = state.lock().await;
+        (
+            state.fedimint_clients(connection).await?,
+            state.shared_wallet(),
+            state.settlements.clone(),
+        )
     };
+    let mut transaction = None;
+    'clients: for client in clients {
+        let mut operations = OperationPager::new(client);
+        while let Some((key, entry)) = operations.next_operation().await {
+            if !operation_visible(&entry, connection, shared_wallet) {
+                continue;
+            }
+            let settled_at = settlements.lock().await.settled_at(&key.operation_id);
+            let found =
+                transaction_from_operation(key.operation_id, key.creation_time, settled_at, &entry)
+                    .map(|(transaction, _)| transaction)
+                    .filter(|transaction| transaction.payment_hash == payment_hash);
+            if found.is_some() {
+                transaction = found;
+                break 'clients;
+            }
+        }
+    }
 
-    let res = lnd
-        .lookup_invoice(PaymentHash {
-            r_hash: payment_hash.clone(),
-            ..Default::default()
-        })
-        .await?
-        .into_inner();
+    match transaction {
+        Some(transaction) => {
+            info!("Looked up invoice: {payment_hash}");
 
-    info!("Looked up invoice: {}", res.payment_request);
+            Ok(Response {
+                result_type: method,
+                error: None,
+                result: Some(ResponseResult::LookupInvoice(transaction)),
+            })
+        }
+        None => Err(NwcError::NotFound(format!(
+            "Invoice not found: {payment_hash}"
+        ))),
+    }
+}
 
-    let (description, description_hash) = match invoice {
-        Some(inv) => match inv.description() {
-            Bolt11InvoiceDescription::Direct(desc) => (Some(desc.to_string()), None),
-            Bolt11InvoiceDescription::Hash(hash) => (None, Some(hash.0.to_string())),
-        },
-        None => (None, None),
+/// Converts an operation from the federation client's operation log into a
+/// NIP-47 transaction, with whether it settled. `settled_at` is when it was
+/// recorded to settle, operations settled without a recorded time are
+/// reported without one. Returns `None` for operations that are not lightning
+/// payments or receives, or ecash reissued into the wallet.
+pub(crate) fn transaction_from_operation(
+    operation_id: OperationId,
+    creation_time: SystemTime,
+    settled_at: Option<u64>,
+    entry: &OperationLogEntry,
+) -> Option<(LookupInvoiceResponseResult, bool)> {
+    let created_at = creation_time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    match entry.operation_module_kind() {
+        kind if kind == fedimint_ln_common::KIND.as_str() => {
+            lightning_transaction(created_at, settled_at, entry)
+        }
+        kind if kind == fedimint_mint_client::KIND.as_str() => {
+            ecash_transaction(operation_id, created_at, settled_at, entry)
+        }
+        _ => None,
+    }
+}
+
+fn lightning_transaction(
+    created_at: u64,
+    settled_at: Option<u64>,
+    entry: &OperationLogEntry,
+) -> Option<(LookupInvoiceResponseResult, bool)> {
+    let meta: LightningOperationMeta = entry.meta();
+    let (transaction_type, invoice, preimage, fees_paid, settled) = match meta.variant {
+        LightningOperationMetaVariant::Pay(pay) => {
+            // payments to invoices of the federation itself record a
+            // different outcome than payments through a gateway
+            let preimage = if pay.is_internal_payment {
+                match entry.outcome::<InternalPayState>() {
+                    Some(InternalPayState::Preimage(preimage)) => Some(hex::encode(preimage.0)),
+                    _ => None,
+                }
+            } else {
+                match entry.outcome::<LnPayState>() {
+                    Some(LnPayState::Success { preimage }) => Some(preimage),
+                    _ => None,
+                }
+            };
+            let settled = preimage.is_some();
+            (
+                TransactionType::Outgoing,
+                pay.invoice,
+                preimage,
+                pay.fee.msats,
+                settled,
+            )
+        }
+        LightningOperationMetaVariant::Receive { invoice, .. } => {
+            // the federation client doesn't keep the preimage of incoming
+            // payments, so only their settlement can be reported
+            let settled = matches!(
+                entry.outcome::<LnReceiveState>(),
+                Some(LnReceiveState::Claimed)
+            );
+            (TransactionType::Incoming, invoice, None, 0, settled)
+        }
+        _ => return None,
     };
 
-    let preimage = if res.r_preimage.is_empty() {
-        None
-    } else {
-        Some(hex::encode(res.r_preimage))
+    let (description, description_hash) = match invoice.description() {
+        Bolt11InvoiceDescription::Direct(desc) => (Some(desc.to_string()), None),
+        Bolt11InvoiceDescription::Hash(hash) => (None, Some(hash.0.to_string())),
     };
 
-    let settled_at = if res.settle_date == 0 {
-        None
-    } else {
-        Some(res.settle_date as u64)
+    // the expiry is up to the invoice's creator and may overflow
+    let expires_at = invoice.expires_at().map_or(u64::MAX, |at| at.as_secs());
+
+    let transaction = LookupInvoiceResponseResult {
+        transaction_type: Some(transaction_type),
+        invoice: Some(invoice.to_string()),
+        description,
+        description_hash,
+        preimage,
+        payment_hash: invoice.payment_hash().to_string(),
+        amount: invoice.amount_milli_satoshis().unwrap_or(0),
+        fees_paid,
+        created_at,
+        expires_at,
+        settled_at: settled_at.filter(|_| settled),
+        metadata: Default::default(),
     };
 
-    Response {
-        result_type: Method::LookupInvoice,
-        error: None,
-        result: Some(ResponseResult::LookupInvoice(LookupInvoiceResponseResult {
-            transaction_type: None,
-            invoice: Some(res.payment_request),
-            description,
-            description_hash,
-            preimage,
-            payment_hash: hex::encode(payment_hash),
-            amount: res.value_msat as u64,
-            fees_paid: 0,
-            created_at: res.creation_date as u64,
-            expires_at: (res.creation_date + res.expiry) as u64,
-            settled_at,
-            metadata: Default::default(),
-        })),
+    Some((transaction, settled))
+}
+
+/// Converts ecash reissued into the wallet into an incoming transaction.
+/// There is no invoice, so the operation id stands in for the payment hash.
+fn ecash_transaction(
+    operation_id: OperationId,
+    created_at: u64,
+    settled_at: Option<u64>,
+    entry: &OperationLogEntry,
+) -> Option<(LookupInvoiceResponseResult, bool)> {
+    let meta: MintOperationMeta = entry.meta();
+    if !matches!(meta.variant, MintOperationMetaVariant::Reissuance { .. }) {
+        return None;
     }
+
+    let settled = matches!(
+        entry.outcome::<ReissueExternalNotesState>(),
+        Some(ReissueExternalNotesState::Done)
+    );
+
+    let transaction = LookupInvoiceResponseResult {
+        transaction_type: Some(TransactionType::Incoming),
+        invoice: None,
+        description: None,
+        description_hash: None,
+        preimage: None,
+        payment_hash: hex::encode(operation_id.0),
+        amount: meta.amount.msats,
+        fees_paid: 0,
+        created_at,
+        expires_at: created_at,
+        settled_at: settled_at.filter(|_| settled),
+        metadata: Default::default(),
+    };
+
+    Some((transaction, settled))
 }
@@ -1,39 +1,88 @@
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::SystemTime;
 
-use hex::FromHex;
+use fedimint_core::bitcoin_hashes::sha256;
+use fedimint_core::Amount;
+use fedimint_ln_client::LightningClientModule;
+use lightning_invoice::{Bolt11InvoiceDescription, Description, Sha256};
 use log::info;
-use nostr::nips::nip47::{Method, RequestParams, Response};
+use nostr::nips::nip47::{
+    MakeInvoiceRequestParams, MakeInvoiceResponseResult, Method, Response, ResponseResult,
+};
 use tokio::sync::Mutex;
 
-use crate::AppState;
+use super::{NwcError, OperationMeta};
+use crate::connections::Connection;
+use crate::{gateways, notifications, AppState};
 
-async fn handle_nwc_make_invoice(
-    params: RequestParams,
+/// How long invoices are valid for when the app doesn't ask otherwise.
+const DEFAULT_EXPIRY: u64 = 86_400;
+/// The longest invoices may be valid for, a year.
+const MAX_EXPIRY: u64 = 365 * 86_400;
+
+pub async fn handle_nwc_make_invoice(
+    params: MakeInvoiceRequestParams,
+    connection: &Connection,
     state: Arc<Mutex<AppState>>,
     method: Method,
-) -> anyhow::Result<Response> {
-    let description_hash: Vec<u8> = match params.description_hash {
-        None => vec![],
-        Some(str) => FromHex::from_hex(&str)?,
+) -> Result<Response, NwcError> {
+    let expiry = params.expiry.unwrap_or(DEFAULT_EXPIRY);
+    if expiry > MAX_EXPIRY {
+        return Err(NwcError::InvalidRequest(format!(
+            "Expiry of {expiry}s is too long, max is {MAX_EXPIRY}s"
+        )));
+    }
+
+    let (client, config) = {
+        let state = state.lock().await;
+        (
+            state.fedimint_client(connection).await?,
+            state.config.clone(),
+        )
+    };
+    let lightning_module = client.get_first_module::<LightningClientModule>();
+    let gateway = gateways::select_gateways(&lightning_module, &config, params.amount)
+        .await?
+        .into_iter()
+        .next();
+
+    let description_hash = match params.description_hash {
+        None => None,
+        Some(str) => Some(Sha256(sha256::Hash::from_str(&str).map_err(|_| {
+            NwcError::InvalidRequest(format!("Invalid description hash: {str}"))
+        })?)),
     };
-    let inv = Invoice {
-        memo: params.description.unwrap_or_default(),
-        description_hash,
-        value_msat: params.amount as i64,
-        expiry: params.expiry.unwrap_or(86_400) as i64,
-        private: config.route_hints,
-        ..Default::default()
+    let description = Description::new(params.description.unwrap_or_default())
+        .map_err(|e| NwcError::InvalidRequest(format!("Invalid description: {e}")))?;
+    let invoice_description = match &description_hash {
+        Some(hash) => Bolt11InvoiceDescription::Hash(hash),
+        None => Bolt11InvoiceDescription::Direct(&description),
     };
-    let res = lnd.add_invoice(inv).await?.into_inner();
 
-    info!("Created invoice: {}", res.payment_request);
+    let created_at = SystemTime::now();
+    let (operation_id, invoice, _) = lightning_module
+        .create_bolt11_invoice(
+            Amount::from_msats(params.amount),
+            invoice_description,
+            Some(expiry),
+            OperationMeta {
+                connection: connection.public_key(),
+            },
+            gateway,
+        )
+        .await?;
+
+    info!("Created invoice: {invoice}");
 
-    Response {
-        result_type: Method::MakeInvoice,
+    notifications::watch_receive(state, client.clone(), operation_id, created_at);
+
+    Ok(Response {
+        result_type: method,
         error: None,
         result: Some(ResponseResult::MakeInvoice(MakeInvoiceResponseResult {
-            invoice: res.payment_request,
-            payment_hash: ::hex::encode(res.r_hash),
+            invoice: invoice.to_string(),
+            payment_hash: invoice.payment_hash().to_string(),
         })),
-    }
+    })
 }
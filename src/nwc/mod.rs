@@ -1,54 +1,485 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use anyhow::anyhow;
-use nostr::nips::nip04;
-use nostr::nips::nip47::{Method, RequestParams};
+use fedimint_client::db::ChronologicalOperationLogKey;
+use fedimint_client::oplog::OperationLogEntry;
+use fedimint_client::ClientHandleArc;
+use fedimint_ln_client::LightningOperationMeta;
+use futures_util::future::join_all;
+use lightning_invoice::Bolt11Invoice;
+use log::{error, info};
+use nostr::nips::nip47::{Method, RequestParams, Response};
+use nostr::{JsonUtil, PublicKey, Timestamp};
 use nostr_sdk::{Event, EventBuilder, Kind, Tag};
-use tokio::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::spawn;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::task::spawn_blocking;
+use tokio::time::timeout;
 
-use crate::AppState;
+use crate::config::Config;
+use crate::connections::Connection;
+use crate::encryption::Encryption;
+use crate::payments::{PaymentTracker, Reservation};
+use crate::{gateways, AppState, METHODS};
 
+mod error;
 mod get_balance;
 mod get_info;
+mod list_transactions;
 mod lookup_invoice;
 mod make_invoice;
 mod pay_invoice;
 mod pay_keysend;
 
+pub use error::NwcError;
+pub(crate) use lookup_invoice::transaction_from_operation;
+
+/// Extra metadata stored with the federation operations started by requests,
+/// tying them to the connection that made the request.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OperationMeta {
+    pub connection: PublicKey,
+}
+
+impl OperationMeta {
+    /// Returns the metadata of a lightning operation, `None` if it wasn't
+    /// started by a request.
+    pub fn from_operation(entry: &OperationLogEntry) -> Option<Self> {
+        if entry.operation_module_kind() != fedimint_ln_common::KIND.as_str() {
+            return None;
+        }
+
+        let meta: LightningOperationMeta = entry.meta();
+        serde_json::from_value(meta.extra_meta).ok()
+    }
+}
+
+/// Returns whether a connection sees an operation, in its transactions and
+/// notifications. Connections sharing the wallet only see the operations they
+/// started, a connection of its own sees every operation.
+pub fn operation_visible(
+    entry: &OperationLogEntry,
+    connection: &Connection,
+    shared_wallet: bool,
+) -> bool {
+    !shared_wallet
+        || OperationMeta::from_operation(entry).map(|meta| meta.connection)
+            == Some(connection.public_key())
+}
+
+/// How many operations are read from the operation log at a time.
+const OPERATION_PAGE_SIZE: usize = 100;
+
+/// Reads a federation's operation log page by page, newest first, so
+/// requests only read as far back as they need to.
+pub struct OperationPager {
+    client: ClientHandleArc,
+    page: VecDeque<(ChronologicalOperationLogKey, OperationLogEntry)>,
+    last_key: Option<ChronologicalOperationLogKey>,
+    exhausted: bool,
+}
+
+impl OperationPager {
+    pub fn new(client: ClientHandleArc) -> Self {
+        OperationPager {
+            client,
+            page: VecDeque::new(),
+            last_key: None,
+            exhausted: false,
+        }
+    }
+
+    /// Returns when the next operation was created, `None` once every
+    /// operation was read.
+    pub async fn peek_creation_time(&mut self) -> Option<SystemTime> {
+        self.read_page().await;
+        self.page.front().map(|(key, _)| key.creation_time)
+    }
+
+    /// Returns the next operation, `None` once every operation was read.
+    pub async fn next_operation(
+        &mut self,
+    ) -> Option<(ChronologicalOperationLogKey, OperationLogEntry)> {
+        self.read_page().await;
+        self.page.pop_front()
+    }
+
+    /// Reads the next page once the current one is used up.
+    async fn read_page(&mut self) {
+        if !self.page.is_empty() || self.exhausted {
+            return;
+        }
+
+        let page = self
+            .client
+            .operation_log()
+            .list_operations(OPERATION_PAGE_SIZE, self.last_key)
+            .await;
+        self.exhausted = page.len() < OPERATION_PAGE_SIZE;
+        self.last_key = page.last().map(|(key, _)| *key).or(self.last_key);
+        self.page.extend(page);
+    }
+}
+
+/// How far in the future requests may be dated, to allow for apps whose
+/// clock runs a little ahead of ours.
+const MAX_CLOCK_SKEW: u64 = 60;
+
+/// How long requests may take before they are answered with an error.
+/// Payments still go on after that, until their outcome is known.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Handles a request and answers it. `reservation` is the budget reserved
+/// for a multi_pay item, released if the item isn't paid, and `permit` its
+/// slot among the items paid at the same time, held until its payment is
+/// done.
 pub async fn handle_nwc(
     params: RequestParams,
     method: Method,
     event: &Event,
+    connection: &Connection,
+    encryption: Encryption,
     state: Arc<Mutex<AppState>>,
+    reservation: Option<Reservation>,
+    permit: Option<OwnedSemaphorePermit>,
 ) -> anyhow::Result<()> {
-    let mut d_tag: Option<Tag> = None;
-    let content = match params {
-        RequestParams::PayInvoice(params) => {
-            pay_invoice::handle_nwc_pay_invoice(params, state, tracker, multimint_client, method)
-                .await
+    let max_request_age = state.lock().await.config.max_request_age;
+
+    let d_tag = d_tag(&params, method);
+
+    let result = match check_request(event, method, connection, max_request_age) {
+        // payments run to completion in their own task, so they are never
+        // cancelled midway and keep their reserved budget until they settle
+        Ok(())
+            if matches!(
+                params,
+                RequestParams::PayInvoice(_) | RequestParams::PayKeysend(_)
+            ) =>
+        {
+            let payment = spawn({
+                let connection = connection.clone();
+                let state = state.clone();
+                async move {
+                    let result = dispatch(params, method, &connection, state, reservation).await;
+                    drop(permit);
+                    result
+                }
+            });
+            match timeout(REQUEST_TIMEOUT, payment).await {
+                Ok(result) => result.unwrap_or_else(|e| {
+                    Err(NwcError::Internal(anyhow!("Payment task failed: {e}")))
+                }),
+                Err(_) => Err(NwcError::Internal(anyhow!(
+                    "Payment still pending after {}s, look it up later for its outcome",
+                    REQUEST_TIMEOUT.as_secs()
+                ))),
+            }
+        }
+        Ok(()) => timeout(
+            REQUEST_TIMEOUT,
+            dispatch(params, method, connection, state.clone(), None),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            Err(NwcError::Internal(anyhow!(
+                "Request timed out after {}s",
+                REQUEST_TIMEOUT.as_secs()
+            )))
+        }),
+        Err(e) => {
+            release_reservation(reservation, connection, &state).await;
+            Err(e)
+        }
+    };
+    let content = result.unwrap_or_else(|e| {
+        match &e {
+            NwcError::Internal(_) => error!("Error handling request {}: {e}", event.id),
+            _ => info!("Request {} failed: {e}", event.id),
+        }
+        e.into_response(method)
+    });
+
+    send_response(content.as_json(), d_tag, event, encryption, &state).await
+}
+
+/// Handles the items of a multi_pay request concurrently, each answered with
+/// its own response. The budget for every invoice, with the most its fees may
+/// be, is reserved at once before paying, so the items are rejected together
+/// if they would exceed the connection's budget and a batch is never paid
+/// partially for that reason.
+pub async fn handle_nwc_multi(
+    items: Vec<RequestParams>,
+    method: Method,
+    event: &Event,
+    connection: &Connection,
+    encryption: Encryption,
+    state: Arc<Mutex<AppState>>,
+) -> anyhow::Result<()> {
+    let (concurrency, max_request_age) = {
+        let state = state.lock().await;
+        (
+            state.config.multi_pay_concurrency.max(1),
+            state.config.max_request_age,
+        )
+    };
+
+    let reservations = match check_request(event, method, connection, max_request_age) {
+        Ok(()) => reserve_items(&items, connection, &state).await,
+        Err(e) => Err(e),
+    };
+    let reservations = match reservations {
+        Ok(reservations) => reservations,
+        Err(e) => {
+            info!("Request {} failed: {e}", event.id);
+            let content = e.into_response(method).as_json();
+            for params in &items {
+                send_response(
+                    content.clone(),
+                    d_tag(params, method),
+                    event,
+                    encryption,
+                    &state,
+                )
+                .await?;
+            }
+            return Ok(());
+        }
+    };
+
+    // payments answered as pending keep running, so they hold on to their
+    // permit until they are done and don't make way for more payments
+    let permits = Arc::new(Semaphore::new(concurrency));
+    let results = join_all(
+        items
+            .into_iter()
+            .zip(reservations)
+            .map(|(params, reservation)| {
+                let permits = permits.clone();
+                let state = state.clone();
+                async move {
+                    let permit = permits.acquire_owned().await?;
+                    handle_nwc(
+                        params,
+                        method,
+                        event,
+                        connection,
+                        encryption,
+                        state,
+                        reservation,
+                        Some(permit),
+                    )
+                    .await
+                }
+            }),
+    )
+    .await;
+
+    // every item was answered unless its response couldn't be sent, which
+    // shouldn't stop the other items
+    for result in results {
+        if let Err(e) = result {
+            error!("Error responding to request {}: {e}", event.id);
         }
+    }
+
+    Ok(())
+}
+
+/// Returns the `d` tag identifying the response to a multi_pay item: its id,
+/// or without one the invoice's payment hash or the keysend's pubkey.
+/// Responses to other requests aren't tagged.
+fn d_tag(params: &RequestParams, method: Method) -> Option<Tag> {
+    if !matches!(method, Method::MultiPayInvoice | Method::MultiPayKeysend) {
+        return None;
+    }
+
+    let identifier = match params {
+        RequestParams::PayInvoice(params) => params.id.clone().or_else(|| {
+            let invoice = Bolt11Invoice::from_str(&params.invoice).ok()?;
+            Some(invoice.payment_hash().to_string())
+        }),
         RequestParams::PayKeysend(params) => {
-            pay_keysend::handle_nwc_pay_keysend(params, state, tracker, multimint_client, method)
+            Some(params.id.clone().unwrap_or_else(|| params.pubkey.clone()))
+        }
+        _ => None,
+    };
+    identifier.map(Tag::Identifier)
+}
+
+/// Reserves the budget for every invoice of a multi_pay request in one go
+/// and returns each item's share.
+async fn reserve_items(
+    items: &[RequestParams],
+    connection: &Connection,
+    state: &Arc<Mutex<AppState>>,
+) -> Result<Vec<Option<Reservation>>, NwcError> {
+    let (tracker, config) = {
+        let mut state = state.lock().await;
+        (state.payment_tracker(connection)?, state.config.clone())
+    };
+
+    let shares = item_shares(items, connection, &config);
+    let mut tracker = tracker.lock().await;
+    reserve_shares(&mut tracker, &shares, connection.budget * 1_000)
+}
+
+/// Returns the budget each item of a multi_pay request needs: an invoice's
+/// amount and the most its fees may be. Items that aren't invoices, and
+/// invoices that fail on their own as they don't parse, are expired or are
+/// above the connection's max amount, need none. They don't hold up the rest
+/// of the batch.
+fn item_shares(
+    items: &[RequestParams],
+    connection: &Connection,
+    config: &Config,
+) -> Vec<Option<u64>> {
+    items
+        .iter()
+        .map(|params| match params {
+            RequestParams::PayInvoice(params) => {
+                let invoice = Bolt11Invoice::from_str(&params.invoice).ok()?;
+                if invoice.is_expired() {
+                    return None;
+                }
+                let msats = invoice
+                    .amount_milli_satoshis()
+                    .or(params.amount)
+                    .unwrap_or(0);
+                if connection.max_amount > 0 && msats > connection.max_amount * 1_000 {
+                    return None;
+                }
+                // the amount of amountless invoices is up to the app
+                Some(msats.saturating_add(gateways::max_fee_msats(config, msats)))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Reserves the total of the shares within `budget` and splits it, returning
+/// a reservation for every item with a share.
+fn reserve_shares(
+    tracker: &mut PaymentTracker,
+    shares: &[Option<u64>],
+    budget: u64,
+) -> Result<Vec<Option<Reservation>>, NwcError> {
+    let amounts: Vec<u64> = shares.iter().flatten().copied().collect();
+    let total = amounts
+        .iter()
+        .try_fold(0u64, |total, amount| total.checked_add(*amount));
+    let Some(total) = total else {
+        return Err(NwcError::QuotaExceeded(
+            "Invoices add up to more than can be paid".to_string(),
+        ));
+    };
+    let Some(reservation) = tracker.reserve(total, budget)? else {
+        return Err(NwcError::QuotaExceeded(tracker.budget_exceeded_message()));
+    };
+    let mut reservations = tracker.split(reservation, &amounts)?.into_iter();
+
+    Ok(shares
+        .iter()
+        .map(|share| share.and_then(|_| reservations.next()))
+        .collect())
+}
+
+/// Gives back the budget reserved for a payment that won't be made.
+pub(super) async fn release_reservation(
+    reservation: Option<Reservation>,
+    connection: &Connection,
+    state: &Arc<Mutex<AppState>>,
+) {
+    let Some(reservation) = reservation else {
+        return;
+    };
+
+    let tracker = match state.lock().await.payment_tracker(connection) {
+        Ok(tracker) => tracker,
+        Err(e) => {
+            error!("Error releasing reserved budget: {e}");
+            return;
+        }
+    };
+    if let Err(e) = tracker.lock().await.release(reservation) {
+        error!("Error releasing reserved budget: {e}");
+    }
+}
+
+async fn dispatch(
+    params: RequestParams,
+    method: Method,
+    connection: &Connection,
+    state: Arc<Mutex<AppState>>,
+    reservation: Option<Reservation>,
+) -> Result<Response, NwcError> {
+    match params {
+        RequestParams::PayInvoice(params) => {
+            pay_invoice::handle_nwc_pay_invoice(params, connection, state, method, reservation)
                 .await
         }
+        RequestParams::PayKeysend(params) => pay_keysend::handle_nwc_pay_keysend(params).await,
         RequestParams::MakeInvoice(params) => {
-            make_invoice::handle_nwc_make_invoice(params, state, method).await
+            make_invoice::handle_nwc_make_invoice(params, connection, state, method).await
         }
         RequestParams::LookupInvoice(params) => {
-            lookup_invoice::handle_nwc_lookup_invoice(params, state, method).await
+            lookup_invoice::handle_nwc_lookup_invoice(params, connection, state, method).await
+        }
+        RequestParams::ListTransactions(params) => {
+            list_transactions::handle_nwc_list_transactions(params, connection, state, method).await
         }
-        RequestParams::GetBalance => get_balance::handle_nwc_get_balance(state, method).await,
-        RequestParams::GetInfo => get_info::handle_nwc_get_info(state, method).await,
-        _ => {
-            return Err(anyhow!("Command not supported"));
+        RequestParams::GetBalance => {
+            get_balance::handle_nwc_get_balance(connection, state, method).await
         }
+        RequestParams::GetInfo => get_info::handle_nwc_get_info(connection, state, method).await,
+        _ => Err(NwcError::NotImplemented(format!(
+            "Method not supported: {method}"
+        ))),
+    }
+}
+
+/// Answers a request that failed before its method was known, `method` is
+/// the one the app asked for if it could be read.
+pub async fn send_error(
+    error: NwcError,
+    method: Option<String>,
+    event: &Event,
+    encryption: Encryption,
+    state: &Arc<Mutex<AppState>>,
+) -> anyhow::Result<()> {
+    info!("Request {} failed: {error}", event.id);
+    let content = json!({
+        "result_type": method,
+        "error": error.to_nip47(),
+    });
+    send_response(content.to_string(), None, event, encryption, state).await
+}
+
+/// Encrypts the response to a request with the request's scheme, records it
+/// and sends it. Requests of unknown apps aren't recorded, and neither are
+/// their responses.
+async fn send_response(
+    content: String,
+    d_tag: Option<Tag>,
+    event: &Event,
+    encryption: Encryption,
+    state: &Arc<Mutex<AppState>>,
+) -> anyhow::Result<()> {
+    let (keys, client, recorded) = {
+        let state = state.lock().await;
+        (
+            state.keys.clone(),
+            state.nostr_client.clone(),
+            state.connections.get(&event.pubkey).is_some(),
+        )
     };
 
-    let encrypted = nip04::encrypt(
-        &keys.server_key.into(),
-        &keys.user_keys().public_key(),
-        content.as_json(),
-    )?;
+    let encrypted =
+        encryption.encrypt(&keys.server_keys().secret_key()?, &event.pubkey, content)?;
     let p_tag = Tag::public_key(event.pubkey);
     let e_tag = Tag::event(event.id);
     let tags = match d_tag {
@@ -58,7 +489,67 @@ pub async fn handle_nwc(
     let response = EventBuilder::new(Kind::WalletConnectResponse, encrypted, tags)
         .to_event(&keys.server_keys())?;
 
+    // record the response before sending it, so a replay of the request gets
+    // it even if we crash right after
+    if recorded {
+        let requests = state.lock().await.requests.clone();
+        let event_id = event.id;
+        let recorded_response = response.clone();
+        spawn_blocking(move || requests.add_response(&event_id, recorded_response)).await??;
+    }
     client.send_event(response).await?;
 
     Ok(())
 }
+
+/// Fails requests that can't be executed: they are too old, or call a method
+/// the connection isn't allowed to. Unsupported methods are answered as not
+/// implemented by their handlers instead.
+fn check_request(
+    event: &Event,
+    method: Method,
+    connection: &Connection,
+    max_request_age: u64,
+) -> Result<(), NwcError> {
+    check_request_age(event, max_request_age)?;
+
+    if METHODS.contains(&method) && !connection.allows(&method) {
+        return Err(NwcError::Restricted(format!(
+            "Connection {} is not allowed to call {method}",
+            connection.name
+        )));
+    }
+
+    Ok(())
+}
+
+/// Fails requests that are too old to be executed: they carry an expiration
+/// tag that has passed, or were created longer than `max_request_age` seconds
+/// ago. Requests dated further in the future than `MAX_CLOCK_SKEW` fail too,
+/// they would never count as old.
+fn check_request_age(event: &Event, max_request_age: u64) -> Result<(), NwcError> {
+    if event.is_expired() {
+        return Err(NwcError::Expired("Request expired".to_string()));
+    }
+
+    let now = Timestamp::now().as_u64();
+    let created_at = event.created_at.as_u64();
+    if created_at > now + MAX_CLOCK_SKEW {
+        return Err(NwcError::InvalidRequest(format!(
+            "Request is dated {}s in the future, check the app's clock",
+            created_at - now
+        )));
+    }
+
+    let age = now.saturating_sub(created_at);
+    if max_request_age > 0 && age > max_request_age {
+        return Err(NwcError::Expired(format!(
+            "Request is {age}s old, max age is {max_request_age}s"
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests;
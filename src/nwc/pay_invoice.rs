@@ -1,72 +1,359 @@
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::SystemTime;
 
-use log::info;
-use multimint::MultiMint;
-use nostr::nips::nip47::{Method, RequestParams, Response};
-use nostr_sdk::Tag;
+use anyhow::anyhow;
+use fedimint_client::ClientHandleArc;
+use fedimint_core::core::OperationId;
+use fedimint_ln_client::{
+    InternalPayState, LightningClientModule, LnPayState, OutgoingLightningPayment, PayType,
+};
+use fedimint_ln_common::LightningGateway;
+use futures_util::StreamExt;
+use lightning_invoice::Bolt11Invoice;
+use log::{error, info, warn};
+use nostr::nips::nip47::{
+    Method, PayInvoiceRequestParams, PayInvoiceResponseResult, Response, ResponseResult,
+};
 use tokio::sync::Mutex;
 
-use crate::payments::PaymentTracker;
-use crate::AppState;
+use super::{release_reservation, NwcError, OperationMeta};
+use crate::config::Config;
+use crate::connections::Connection;
+use crate::gateways::{self, routing_fee_msats};
+use crate::payments::Reservation;
+use crate::{notifications, AppState};
 
-async fn handle_nwc_pay_invoice(
-    params: RequestParams,
+/// Pays an invoice within the connection's budget. `reservation` is the
+/// budget already reserved for it as part of a multi_pay batch, otherwise
+/// the payment reserves its own.
+pub async fn handle_nwc_pay_invoice(
+    params: PayInvoiceRequestParams,
+    connection: &Connection,
     state: Arc<Mutex<AppState>>,
-    tracker: Arc<Mutex<PaymentTracker>>,
-    multimint_client: MultiMint,
     method: Method,
-) -> anyhow::Result<Response> {
-    d_tag = params.id.map(Tag::Identifier);
+    reservation: Option<Reservation>,
+) -> Result<Response, NwcError> {
+    let checked = check_invoice(&params, connection, &state).await;
+    let (invoice, msats, clients, config) = match checked {
+        Ok(checked) => checked,
+        Err(e) => {
+            release_reservation(reservation, connection, &state).await;
+            return Err(e);
+        }
+    };
+    let tracker = state.lock().await.payment_tracker(connection)?;
+
+    // reserve the amount and the most the fees may be before paying, so
+    // concurrent payments can't together exceed the budget
+    let reservation = match reservation {
+        Some(reservation) => reservation,
+        None => {
+            let mut tracker = tracker.lock().await;
+            let reserved_msats = msats.saturating_add(gateways::max_fee_msats(&config, msats));
+            match tracker.reserve(reserved_msats, connection.budget * 1_000)? {
+                Some(reservation) => reservation,
+                None => return Err(NwcError::QuotaExceeded(tracker.budget_exceeded_message())),
+            }
+        }
+    };
+
+    let created_at = SystemTime::now();
+    let result = pay_from_federations(&invoice, msats, clients, connection, &config).await;
+    let mut tracker = tracker.lock().await;
+    match result {
+        Ok((client, operation_id, preimage, fee_paid_msats)) => {
+            // the payment went through so still respond if it couldn't be
+            // persisted
+            if let Err(e) = tracker.commit(reservation, msats + fee_paid_msats) {
+                error!("Error persisting payment: {e}");
+            }
+            drop(tracker);
+
+            notifications::notify_operation(state, client, operation_id, created_at);
+
+            Ok(Response {
+                result_type: method,
+                error: None,
+                result: Some(ResponseResult::PayInvoice(PayInvoiceResponseResult {
+                    preimage,
+                })),
+            })
+        }
+        // nothing was paid, so the budget is free again
+        Err(e @ (NwcError::PaymentFailed(_) | NwcError::InsufficientBalance(_))) => {
+            if let Err(e) = tracker.release(reservation) {
+                error!("Error releasing reserved budget: {e}");
+            }
+            Err(e)
+        }
+        // the payment may still go through, so it keeps counting in full
+        Err(e) => {
+            let amount = reservation.amount();
+            if let Err(e) = tracker.commit(reservation, amount) {
+                error!("Error persisting payment: {e}");
+            }
+            Err(e)
+        }
+    }
+}
 
-    let invoice =
-        Bolt11Invoice::from_str(&params.invoice).map_err(|_| anyhow!("Failed to parse invoice"))?;
+/// Parses the invoice and checks it can be paid by the connection, returning
+/// it with the amount to pay, the clients to pay from and the config.
+async fn check_invoice(
+    params: &PayInvoiceRequestParams,
+    connection: &Connection,
+    state: &Arc<Mutex<AppState>>,
+) -> Result<(Bolt11Invoice, u64, Vec<ClientHandleArc>, Config), NwcError> {
+    let invoice = Bolt11Invoice::from_str(&params.invoice)
+        .map_err(|_| NwcError::InvalidRequest("Failed to parse invoice".to_string()))?;
+    if invoice.is_expired() {
+        return Err(NwcError::Expired("Invoice expired".to_string()));
+    }
     let msats = invoice
         .amount_milli_satoshis()
         .or(params.amount)
         .unwrap_or(0);
 
-    let error_msg = if state.lock().await.config.max_amount > 0
-        && msats > state.lock().await.config.max_amount * 1_000
-    {
-        Some("Invoice amount too high.")
-    } else if state.lock().await.config.daily_limit > 0
-        && tracker.lock().await.sum_payments() + msats > config.daily_limit * 1_000
-    {
-        Some("Daily limit exceeded.")
+    if connection.max_amount > 0 && msats > connection.max_amount * 1_000 {
+        return Err(NwcError::QuotaExceeded(
+            "Invoice amount too high.".to_string(),
+        ));
+    }
+
+    let (clients, config) = {
+        let state = state.lock().await;
+        (
+            state.fedimint_clients(connection).await?,
+            state.config.clone(),
+        )
+    };
+
+    Ok((invoice, msats, clients, config))
+}
+
+/// Pays the invoice from the first federation, in order of preference, that
+/// holds enough ecash. Returns the client of the federation paid from, the
+/// operation, the preimage and the fee paid.
+async fn pay_from_federations(
+    invoice: &Bolt11Invoice,
+    msats: u64,
+    clients: Vec<ClientHandleArc>,
+    connection: &Connection,
+    config: &Config,
+) -> Result<(ClientHandleArc, OperationId, String, u64), NwcError> {
+    let mut last_error = None;
+    for client in clients {
+        let balance_msats = client.get_balance().await.msats;
+        if balance_msats < msats {
+            last_error = Some(NwcError::InsufficientBalance(format!(
+                "Balance of {balance_msats}msats is too low to pay {msats}msats"
+            )));
+            continue;
+        }
+
+        info!("Paying invoice from federation {}", client.federation_id());
+        match pay_from_federation(invoice, msats, &client, connection, config).await {
+            Ok((operation_id, preimage, fee_paid_msats)) => {
+                return Ok((client, operation_id, preimage, fee_paid_msats));
+            }
+            // short of the fees, another federation may have enough
+            Err(e @ NwcError::InsufficientBalance(_)) => last_error = Some(e),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(last_error
+        .unwrap_or_else(|| NwcError::InsufficientBalance("No federation to pay from".to_string())))
+}
+
+/// Pays the invoice from a federation, trying its gateways in turn, and
+/// returns the operation, the preimage and the fee paid.
+async fn pay_from_federation(
+    invoice: &Bolt11Invoice,
+    msats: u64,
+    client: &ClientHandleArc,
+    connection: &Connection,
+    config: &Config,
+) -> Result<(OperationId, String, u64), NwcError> {
+    let lightning_module = client.get_first_module::<LightningClientModule>();
+    let max_fee_msats = gateways::max_fee_msats(config, msats);
+    // payments to invoices of the federation itself don't need a gateway.
+    // Without a qualifying gateway the federation client still gets to decide
+    // whether it pays the invoice internally.
+    let mut no_gateway = None;
+    let candidates: Vec<_> = if gateways::pays_internally(client, invoice).await {
+        vec![None]
     } else {
-        None
+        match qualifying_gateways(&lightning_module, config, msats, max_fee_msats).await {
+            Ok(gateways) => gateways.into_iter().map(Some).collect(),
+            Err(reason) => {
+                no_gateway = Some(reason);
+                vec![None]
+            }
+        }
     };
 
-    // verify amount, convert to msats
-    match error_msg {
-        None => {
-            match pay_invoice(invoice, multimint_client, method).await {
-                Ok(content) => {
-                    // add payment to tracker
-                    tracker.lock().await.add_payment(msats);
-                    content
+    let mut last_error = None;
+    for gateway in candidates {
+        let fee_msats = gateway
+            .as_ref()
+            .map_or(0, |gateway| routing_fee_msats(&gateway.fees, msats));
+        // a cheaper gateway further down may still fit the balance
+        let balance_msats = client.get_balance().await.msats;
+        if balance_msats < msats.saturating_add(fee_msats) {
+            last_error = Some(NwcError::InsufficientBalance(format!(
+                "Balance of {balance_msats}msats is too low to pay {msats}msats plus {fee_msats}msats in fees"
+            )));
+            continue;
+        }
+
+        let gateway_id = gateway
+            .as_ref()
+            .map_or("none".to_string(), |gateway| gateway.gateway_id.to_string());
+        info!("Paying invoice through gateway {gateway_id}");
+
+        match pay_invoice(invoice.clone(), gateway, client, connection).await {
+            Ok((operation_id, preimage, fee_paid_msats)) => {
+                info!("Paid invoice with {fee_paid_msats}msats in fees, preimage: {preimage}");
+                return Ok((operation_id, preimage, fee_paid_msats));
+            }
+            // the payment definitely failed, so another gateway can try
+            Err(NwcError::PaymentFailed(e)) => {
+                warn!("Payment through gateway {gateway_id} failed: {e}");
+                let e = match &no_gateway {
+                    Some(reason) => format!("{reason}, {e}"),
+                    None => e,
+                };
+                last_error = Some(NwcError::PaymentFailed(e));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| NwcError::PaymentFailed("Payment failed".to_string())))
+}
+
+/// Returns the gateways that may route `msats` for at most `max_fee_msats`,
+/// or why there are none.
+async fn qualifying_gateways(
+    lightning_module: &LightningClientModule,
+    config: &Config,
+    msats: u64,
+    max_fee_msats: u64,
+) -> Result<Vec<LightningGateway>, String> {
+    let gateways: Vec<_> = gateways::select_gateways(lightning_module, config, msats)
+        .await
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .filter(|gateway| routing_fee_msats(&gateway.fees, msats) <= max_fee_msats)
+        .collect();
+    if gateways.is_empty() {
+        return Err(format!(
+            "Every gateway charges more than the max fee of {max_fee_msats}msats"
+        ));
+    }
+
+    Ok(gateways)
+}
+
+/// Pays the invoice through the federation and waits for the outgoing
+/// contract to resolve, returning the operation, the preimage and the fee
+/// paid on success.
+async fn pay_invoice(
+    invoice: Bolt11Invoice,
+    gateway: Option<LightningGateway>,
+    client: &ClientHandleArc,
+    connection: &Connection,
+) -> Result<(OperationId, String, u64), NwcError> {
+    let lightning_module = client.get_first_module::<LightningClientModule>();
+
+    let OutgoingLightningPayment {
+        payment_type, fee, ..
+    } = lightning_module
+        .pay_bolt11_invoice(
+            gateway,
+            invoice,
+            OperationMeta {
+                connection: connection.public_key(),
+            },
+        )
+        .await
+        .map_err(|e| NwcError::PaymentFailed(format!("Failed to pay invoice: {e}")))?;
+
+    // the update streams are driven to the end so the federation client
+    // records the outcome in the operation log
+    match payment_type {
+        PayType::Internal(operation_id) => {
+            let mut updates = lightning_module
+                .subscribe_internal_pay(operation_id)
+                .await?
+                .into_stream();
+
+            let mut result = Err(NwcError::Internal(anyhow!(
+                "Internal payment did not resolve"
+            )));
+            while let Some(update) = updates.next().await {
+                match update {
+                    InternalPayState::Preimage(preimage) => {
+                        result = Ok((operation_id, hex::encode(preimage.0), fee.msats));
+                    }
+                    InternalPayState::RefundSuccess { error, .. } => {
+                        result = Err(NwcError::PaymentFailed(format!(
+                            "Internal payment refunded: {error:?}"
+                        )));
+                    }
+                    InternalPayState::RefundError { error_message, .. } => {
+                        result = Err(NwcError::PaymentFailed(format!(
+                            "Internal payment refund failed: {error_message}"
+                        )));
+                    }
+                    InternalPayState::FundingFailed { error } => {
+                        result = Err(NwcError::PaymentFailed(format!(
+                            "Internal payment funding failed: {error:?}"
+                        )));
+                    }
+                    InternalPayState::UnexpectedError(e) => {
+                        result = Err(NwcError::PaymentFailed(format!(
+                            "Internal payment failed: {e}"
+                        )));
+                    }
+                    _ => {}
                 }
-                Err(e) => {
-                    error!("Error paying invoice: {e}");
-
-                    Response {
-                        result_type: method,
-                        error: Some(NIP47Error {
-                            code: ErrorCode::InsufficientBalance,
-                            message: format!("Failed to pay invoice: {e}"),
-                        }),
-                        result: None,
+            }
+            result
+        }
+        PayType::Lightning(operation_id) => {
+            let mut updates = lightning_module
+                .subscribe_ln_pay(operation_id)
+                .await?
+                .into_stream();
+
+            // without a final state the payment may still go through, so it
+            // mustn't be retried through another gateway
+            let mut result = Err(NwcError::Internal(anyhow!("Payment did not resolve")));
+            while let Some(update) = updates.next().await {
+                match update {
+                    LnPayState::Success { preimage } => {
+                        result = Ok((operation_id, preimage, fee.msats));
+                    }
+                    LnPayState::Refunded { gateway_error } => {
+                        result = Err(NwcError::PaymentFailed(format!(
+                            "Payment refunded: {gateway_error:?}"
+                        )));
+                    }
+                    LnPayState::Canceled => {
+                        result = Err(NwcError::PaymentFailed("Payment canceled".to_string()));
+                    }
+                    LnPayState::UnexpectedError { error_message } => {
+                        result = Err(NwcError::Internal(anyhow!(
+                            "Payment failed: {error_message}"
+                        )));
                     }
+                    _ => {}
                 }
             }
+            result
         }
-        Some(err_msg) => Response {
-            result_type: method,
-            error: Some(NIP47Error {
-                code: ErrorCode::QuotaExceeded,
-                message: err_msg.to_string(),
-            }),
-            result: None,
-        },
     }
 }
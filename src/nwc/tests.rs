@@ -0,0 +1,297 @@
+use clap::Parser;
+use nostr::nips::nip47::{PayInvoiceRequestParams, PayKeysendRequestParams};
+use nostr::Keys;
+
+use super::*;
+use crate::connections::Permissions;
+use crate::payments::{BudgetPeriod, BudgetRenewal};
+use crate::test_utils::{tracker, TempDir};
+
+/// Invoice for 2500 uBTC from the BOLT 11 test vectors, long expired.
+const INVOICE: &str = "lnbc2500u1pvjluezsp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpu9qrsgquk0rl77nj30yxdy8j9vdx85fkpmdla2087ne0xh8nhedh8w27kyke0lp53ut353s06fv3qfegext0eh0ymjpf39tuven09sam30g4vgpfna3rh";
+
+/// Returns a request created `age` seconds ago, with the extra tags.
+fn request(age: u64, tags: Vec<Tag>) -> Event {
+    request_at(Timestamp::from(Timestamp::now().as_u64() - age), tags)
+}
+
+fn request_at(created_at: Timestamp, tags: Vec<Tag>) -> Event {
+    EventBuilder::new(Kind::WalletConnectRequest, "", tags)
+        .custom_created_at(created_at)
+        .to_event(&Keys::generate())
+        .unwrap()
+}
+
+#[test]
+fn recent_request_is_accepted() {
+    assert!(check_request_age(&request(10, vec![]), 600).is_ok());
+}
+
+#[test]
+fn old_request_is_expired() {
+    let result = check_request_age(&request(601, vec![]), 600);
+    assert!(matches!(result, Err(NwcError::Expired(_))));
+}
+
+#[test]
+fn max_age_of_zero_accepts_any_age() {
+    assert!(check_request_age(&request(86_400, vec![]), 0).is_ok());
+}
+
+#[test]
+fn passed_expiration_tag_expires_request() {
+    let expiration = Timestamp::from(Timestamp::now().as_u64() - 1);
+    let event = request(10, vec![Tag::Expiration(expiration)]);
+
+    let result = check_request_age(&event, 0);
+    assert!(matches!(result, Err(NwcError::Expired(_))));
+}
+
+#[test]
+fn future_expiration_tag_is_accepted() {
+    let expiration = Timestamp::from(Timestamp::now().as_u64() + 60);
+    let event = request(10, vec![Tag::Expiration(expiration)]);
+
+    assert!(check_request_age(&event, 600).is_ok());
+}
+
+#[test]
+fn slightly_future_request_is_accepted() {
+    let event = request_at(Timestamp::from(Timestamp::now().as_u64() + 30), vec![]);
+
+    assert!(check_request_age(&event, 600).is_ok());
+}
+
+#[test]
+fn far_future_request_is_rejected() {
+    let created_at = Timestamp::from(Timestamp::now().as_u64() + MAX_CLOCK_SKEW + 60);
+    let event = request_at(created_at, vec![]);
+
+    // whatever the max age, as it would never count as old
+    for max_request_age in [600, 0] {
+        let result = check_request_age(&event, max_request_age);
+        assert!(matches!(result, Err(NwcError::InvalidRequest(_))));
+    }
+}
+
+/// The generator point, a valid node public key.
+const NODE_PUBKEY: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+fn connection(permissions: Permissions) -> Connection {
+    Connection::new(
+        "test".to_string(),
+        0,
+        0,
+        BudgetRenewal::Daily,
+        false,
+        permissions.methods(),
+        None,
+        Vec::new(),
+    )
+    .unwrap()
+}
+
+#[test]
+fn allowed_method_is_accepted() {
+    let connection = connection(Permissions::ReceiveOnly);
+
+    assert!(check_request(&request(0, vec![]), Method::MakeInvoice, &connection, 600).is_ok());
+}
+
+#[test]
+fn disallowed_method_is_restricted() {
+    let connection = connection(Permissions::ReceiveOnly);
+
+    let result = check_request(&request(0, vec![]), Method::PayInvoice, &connection, 600);
+    assert!(matches!(result, Err(NwcError::Restricted(_))));
+}
+
+#[test]
+fn multi_pay_is_restricted_for_read_only_connections() {
+    let connection = connection(Permissions::ReadOnly);
+
+    let result = check_request(
+        &request(0, vec![]),
+        Method::MultiPayInvoice,
+        &connection,
+        600,
+    );
+    assert!(matches!(result, Err(NwcError::Restricted(_))));
+}
+
+#[tokio::test]
+async fn unsupported_method_is_not_implemented() {
+    // no connection is allowed to call it, yet it isn't restricted
+    for permissions in [Permissions::Full, Permissions::ReadOnly] {
+        let connection = connection(permissions);
+        let result = check_request(&request(0, vec![]), Method::PayKeysend, &connection, 600);
+        assert!(result.is_ok());
+    }
+
+    let params = PayKeysendRequestParams {
+        id: None,
+        amount: 1_000,
+        pubkey: NODE_PUBKEY.to_string(),
+        preimage: None,
+        tlv_records: Vec::new(),
+    };
+    let result = pay_keysend::handle_nwc_pay_keysend(params).await;
+    assert!(matches!(result, Err(NwcError::NotImplemented(_))));
+}
+
+fn pay_invoice(invoice: &str) -> RequestParams {
+    RequestParams::PayInvoice(PayInvoiceRequestParams {
+        id: None,
+        invoice: invoice.to_string(),
+        amount: None,
+    })
+}
+
+/// Amountless invoice that expires in 2123.
+const AMOUNTLESS_INVOICE: &str = "lnbc1pj48ugqdqjwph8j7r5wgs8getnwspp5yg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3qsp5xvenxvenxvenxvenxvenxvenxvenxvenxvenxvenxvenxvenxves9qrsgqxq8zals8sqcqzysr6j53jqk5dmurcuf5cfjueeyf6rpczdxruu7jylj8vf3h5ee3shj0wgd5hkw3v5mvf7ap3st3d0e2x7wvr5wvcs56lg4zjpen89hw4sqwqz737";
+/// Invoice for 2500 uBTC that expires in 2123.
+const UNEXPIRED_INVOICE: &str = "lnbc2500u1pj48ugqdqjwph8j7r5wgs8getnwspp5yg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3qsp5xvenxvenxvenxvenxvenxvenxvenxvenxvenxvenxvenxvenxves9qrsgqxq8zals8sqcqzysjtuhsuskvnw8vurjswdudcw08urgf4y95nza80ygkcm6l4h8qhz5wl9nwutcwgu974a5aqz3gg90stkx4nr5tudpt8ue73tvlsahlhcq7h0py4";
+
+fn daily() -> BudgetPeriod {
+    BudgetPeriod {
+        renewal: BudgetRenewal::Daily,
+        calendar_aligned: false,
+    }
+}
+
+#[test]
+fn only_invoices_get_a_share() {
+    let config = Config::parse_from(["pnyxtr", "--data-dir", "data"]);
+    let keysend = RequestParams::PayKeysend(PayKeysendRequestParams {
+        id: None,
+        amount: 1_000,
+        pubkey: Keys::generate().public_key().to_string(),
+        preimage: None,
+        tlv_records: Vec::new(),
+    });
+    let items = [
+        pay_invoice(UNEXPIRED_INVOICE),
+        keysend,
+        pay_invoice("not an invoice"),
+    ];
+
+    // the amount and 1% of it for fees
+    assert_eq!(
+        item_shares(&items, &connection(Permissions::Full), &config),
+        [Some(250_000_000 + 2_500_000), None, None]
+    );
+}
+
+#[test]
+fn invoices_failing_on_their_own_get_no_share() {
+    let config = Config::parse_from(["pnyxtr", "--data-dir", "data"]);
+    let mut connection = connection(Permissions::Full);
+    connection.max_amount = 1_000;
+
+    // expired, and above the max amount of 1000sats
+    let items = [pay_invoice(INVOICE), pay_invoice(UNEXPIRED_INVOICE)];
+    assert_eq!(item_shares(&items, &connection, &config), [None, None]);
+}
+
+#[test]
+fn huge_amount_saturates_its_share() {
+    let config = Config::parse_from(["pnyxtr", "--data-dir", "data"]);
+    let item = RequestParams::PayInvoice(PayInvoiceRequestParams {
+        id: None,
+        invoice: AMOUNTLESS_INVOICE.to_string(),
+        amount: Some(u64::MAX),
+    });
+
+    assert_eq!(
+        item_shares(&[item], &connection(Permissions::Full), &config),
+        [Some(u64::MAX)]
+    );
+}
+
+#[test]
+fn items_keep_their_own_share() {
+    let dir = TempDir::new("shares");
+    let mut tracker = tracker(&dir, daily());
+
+    let shares = [Some(3_000), None, Some(2_000)];
+    let reservations = reserve_shares(&mut tracker, &shares, 10_000).unwrap();
+
+    let amounts: Vec<_> = reservations
+        .iter()
+        .map(|reservation| reservation.as_ref().map(Reservation::amount))
+        .collect();
+    assert_eq!(amounts, shares);
+    assert_eq!(tracker.spent(), 5_000);
+}
+
+#[test]
+fn batch_over_budget_reserves_nothing() {
+    let dir = TempDir::new("shares-over-budget");
+    let mut tracker = tracker(&dir, daily());
+
+    let result = reserve_shares(&mut tracker, &[Some(6_000), Some(5_000)], 10_000);
+    assert!(matches!(result, Err(NwcError::QuotaExceeded(_))));
+    assert_eq!(tracker.spent(), 0);
+}
+
+fn pay_keysend(id: Option<&str>) -> RequestParams {
+    RequestParams::PayKeysend(PayKeysendRequestParams {
+        id: id.map(str::to_string),
+        amount: 1_000,
+        pubkey: NODE_PUBKEY.to_string(),
+        preimage: None,
+        tlv_records: Vec::new(),
+    })
+}
+
+#[test]
+fn multi_pay_items_are_tagged_with_their_id() {
+    let invoice = RequestParams::PayInvoice(PayInvoiceRequestParams {
+        id: Some("first".to_string()),
+        invoice: INVOICE.to_string(),
+        amount: None,
+    });
+
+    assert_eq!(
+        d_tag(&invoice, Method::MultiPayInvoice),
+        Some(Tag::Identifier("first".to_string()))
+    );
+    assert_eq!(
+        d_tag(&pay_keysend(Some("second")), Method::MultiPayKeysend),
+        Some(Tag::Identifier("second".to_string()))
+    );
+}
+
+#[test]
+fn multi_pay_items_without_id_are_tagged_with_their_target() {
+    let payment_hash = Bolt11Invoice::from_str(INVOICE)
+        .unwrap()
+        .payment_hash()
+        .to_string();
+
+    assert_eq!(
+        d_tag(&pay_invoice(INVOICE), Method::MultiPayInvoice),
+        Some(Tag::Identifier(payment_hash))
+    );
+    assert_eq!(
+        d_tag(&pay_keysend(None), Method::MultiPayKeysend),
+        Some(Tag::Identifier(NODE_PUBKEY.to_string()))
+    );
+}
+
+#[test]
+fn single_payments_are_not_tagged() {
+    assert_eq!(d_tag(&pay_invoice(INVOICE), Method::PayInvoice), None);
+    assert_eq!(d_tag(&pay_keysend(Some("id")), Method::PayKeysend), None);
+}
+
+#[test]
+fn overflowing_batch_exceeds_the_quota() {
+    let dir = TempDir::new("shares-overflow");
+    let mut tracker = tracker(&dir, daily());
+
+    // even without a budget
+    let result = reserve_shares(&mut tracker, &[Some(u64::MAX), Some(1)], 0);
+    assert!(matches!(result, Err(NwcError::QuotaExceeded(_))));
+    assert_eq!(tracker.spent(), 0);
+}
@@ -1,35 +1,297 @@
 use std::collections::VecDeque;
-use std::time::{Duration, Instant};
+use std::fmt;
+use std::fs::{create_dir_all, rename, File};
+use std::io::{BufReader, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-const CACHE_DURATION: Duration = Duration::from_secs(86_400); // 1 day
+use anyhow::Context;
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, SecondsFormat};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 
+/// How often a connection's budget renews, matching the NIP-47 budget
+/// renewal periods.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetRenewal {
+    #[default]
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+    Never,
+}
+
+impl BudgetRenewal {
+    /// Length of the sliding window for this renewal period, `None` if the
+    /// budget never renews.
+    fn window(&self) -> Option<Duration> {
+        let days = match self {
+            BudgetRenewal::Daily => 1,
+            BudgetRenewal::Weekly => 7,
+            BudgetRenewal::Monthly => 30,
+            BudgetRenewal::Yearly => 365,
+            BudgetRenewal::Never => return None,
+        };
+        Some(Duration::from_secs(days * 86_400))
+    }
+}
+
+impl fmt::Display for BudgetRenewal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let str = match self {
+            BudgetRenewal::Daily => "daily",
+            BudgetRenewal::Weekly => "weekly",
+            BudgetRenewal::Monthly => "monthly",
+            BudgetRenewal::Yearly => "yearly",
+            BudgetRenewal::Never => "total",
+        };
+        write!(f, "{str}")
+    }
+}
+
+/// The period payments are counted over for a budget, either a sliding window
+/// or a calendar period (UTC) that resets at its start.
+#[derive(Debug, Clone, Copy)]
+pub struct BudgetPeriod {
+    pub renewal: BudgetRenewal,
+    pub calendar_aligned: bool,
+}
+
+impl BudgetPeriod {
+    /// Returns the start of the calendar period containing `date`.
+    fn calendar_start(&self, date: NaiveDate) -> Option<NaiveDate> {
+        match self.renewal {
+            BudgetRenewal::Daily => Some(date),
+            BudgetRenewal::Weekly => {
+                date.checked_sub_days(Days::new(date.weekday().num_days_from_monday() as u64))
+            }
+            BudgetRenewal::Monthly => date.with_day(1),
+            BudgetRenewal::Yearly => date.with_ordinal(1),
+            BudgetRenewal::Never => None,
+        }
+    }
+
+    /// Returns the start of the calendar period after the one starting at
+    /// `start`.
+    fn calendar_next(&self, start: NaiveDate) -> Option<NaiveDate> {
+        match self.renewal {
+            BudgetRenewal::Daily => start.checked_add_days(Days::new(1)),
+            BudgetRenewal::Weekly => start.checked_add_days(Days::new(7)),
+            BudgetRenewal::Monthly => start.checked_add_months(Months::new(1)),
+            BudgetRenewal::Yearly => start.checked_add_months(Months::new(12)),
+            BudgetRenewal::Never => None,
+        }
+    }
+
+    /// Returns the earliest time of payments that count toward the budget at
+    /// `now`, `None` if every payment counts.
+    fn start(&self, now: u64) -> Option<u64> {
+        let window = self.renewal.window()?;
+        if !self.calendar_aligned {
+            return Some(now.saturating_sub(window.as_secs()));
+        }
+
+        let date = DateTime::from_timestamp(now as i64, 0)?.date_naive();
+        let start = self.calendar_start(date)?;
+        Some(start.and_hms_opt(0, 0, 0)?.and_utc().timestamp() as u64)
+    }
+}
+
+/// Formats a unix timestamp for error messages.
+fn format_timestamp(time: u64) -> String {
+    DateTime::from_timestamp(time as i64, 0)
+        .map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true))
+        .unwrap_or_else(|| time.to_string())
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 struct Payment {
-    time: Instant,
+    /// Unix timestamp of the payment, in seconds
+    time: u64,
     amount: u64,
+    /// Id of the reservation while the payment is in flight
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reservation: Option<u64>,
 }
 
+/// Budget reserved for a payment in flight, to be committed or released once
+/// its outcome is known.
+#[derive(Debug)]
+pub struct Reservation {
+    id: u64,
+    amount: u64,
+}
+
+impl Reservation {
+    pub fn amount(&self) -> u64 {
+        self.amount
+    }
+}
+
+/// Tracks the payments made by a connection, persisted to disk so budgets
+/// survive restarts.
+///
+/// Payments in flight reserve their amount first, so concurrent payments
+/// can't together exceed the budget. Reservations are committed once the
+/// payment succeeded and released if it definitely failed. They are persisted
+/// like payments, so a payment interrupted by a crash still counts.
 pub struct PaymentTracker {
     payments: VecDeque<Payment>,
+    next_reservation: u64,
+    period: BudgetPeriod,
+    path: PathBuf,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
 }
 
 impl PaymentTracker {
-    pub fn new() -> Self {
-        PaymentTracker {
-            payments: VecDeque::new(),
+    /// Loads the payments tracked in the specified file, starting with no
+    /// payments if the file does not exist.
+    pub fn load(path: PathBuf, period: BudgetPeriod) -> Result<Self, anyhow::Error> {
+        let payments: VecDeque<Payment> = match File::open(&path) {
+            Ok(file) => {
+                let reader = BufReader::new(file);
+                serde_json::from_reader(reader).context("Could not parse JSON")?
+            }
+            Err(_) => VecDeque::new(),
+        };
+        let next_reservation = payments
+            .iter()
+            .filter_map(|p| p.reservation)
+            .max()
+            .map_or(0, |id| id + 1);
+
+        Ok(PaymentTracker {
+            payments,
+            next_reservation,
+            period,
+            path,
+        })
+    }
+
+    /// Reserves `amount` for a payment, returning `None` if it doesn't fit in
+    /// `budget` next to the payments made and in flight. A budget of 0 is
+    /// unlimited.
+    pub fn reserve(
+        &mut self,
+        amount: u64,
+        budget: u64,
+    ) -> Result<Option<Reservation>, anyhow::Error> {
+        if budget > 0 && self.spent().saturating_add(amount) > budget {
+            return Ok(None);
+        }
+
+        let reservation = self.push_reservation(now(), amount);
+        self.write_payments()?;
+        Ok(Some(reservation))
+    }
+
+    fn push_reservation(&mut self, time: u64, amount: u64) -> Reservation {
+        let id = self.next_reservation;
+        self.next_reservation += 1;
+
+        // the payments are kept in time order, a split reservation may be
+        // older than the latest payments
+        let index = self.payments.partition_point(|p| p.time <= time);
+        self.payments.insert(
+            index,
+            Payment {
+                time,
+                amount,
+                reservation: Some(id),
+            },
+        );
+        Reservation { id, amount }
+    }
+
+    /// Turns a reservation into a payment of `amount`, once the payment went
+    /// through. The amount can be less than the reserved one, as fees are
+    /// reserved before they are known.
+    pub fn commit(&mut self, reservation: Reservation, amount: u64) -> Result<(), anyhow::Error> {
+        match self
+            .payments
+            .iter_mut()
+            .find(|p| p.reservation == Some(reservation.id))
+        {
+            Some(payment) => {
+                payment.amount = amount;
+                payment.reservation = None;
+            }
+            // the reservation already left the budget period
+            None => return Ok(()),
         }
+
+        self.write_payments()
+    }
+
+    /// Gives up a reservation, for a payment that definitely failed.
+    pub fn release(&mut self, reservation: Reservation) -> Result<(), anyhow::Error> {
+        self.payments
+            .retain(|p| p.reservation != Some(reservation.id));
+        self.write_payments()
     }
 
-    pub fn add_payment(&mut self, amount: u64) {
-        let now = Instant::now();
-        let payment = Payment { time: now, amount };
+    /// Splits a reservation into one per amount, for payments reserved
+    /// together. Whatever the amounts leave over is released.
+    pub fn split(
+        &mut self,
+        reservation: Reservation,
+        amounts: &[u64],
+    ) -> Result<Vec<Reservation>, anyhow::Error> {
+        let time = self
+            .payments
+            .iter()
+            .find(|p| p.reservation == Some(reservation.id))
+            .map_or_else(now, |p| p.time);
+        self.payments
+            .retain(|p| p.reservation != Some(reservation.id));
 
-        self.payments.push_back(payment);
+        let mut remaining = reservation.amount;
+        let reservations = amounts
+            .iter()
+            .map(|&amount| {
+                let amount = amount.min(remaining);
+                remaining -= amount;
+                self.push_reservation(time, amount)
+            })
+            .collect();
+        self.write_payments()?;
+        Ok(reservations)
+    }
+
+    /// Writes the payments to a temporary file first and moves it into place,
+    /// so a crash mid-write can't lose the tracked payments.
+    fn write_payments(&self) -> Result<(), anyhow::Error> {
+        let json_str = serde_json::to_string(&self.payments).context("Could not serialize data")?;
+
+        if let Some(parent) = self.path.parent() {
+            create_dir_all(parent).context("Could not create directory")?;
+        }
+
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp_path).context("Could not create file")?;
+        file.write_all(json_str.as_bytes())
+            .context("Could not write to file")?;
+        file.sync_all().context("Could not sync file")?;
+        rename(&tmp_path, &self.path).context("Could not move file into place")?;
+
+        Ok(())
     }
 
     fn clean_old_payments(&mut self) {
-        let now = Instant::now();
+        let Some(start) = self.period.start(now()) else {
+            return;
+        };
+
         while let Some(payment) = self.payments.front() {
-            if now.duration_since(payment.time) < CACHE_DURATION {
+            if payment.time >= start {
                 break;
             }
 
@@ -37,8 +299,46 @@ impl PaymentTracker {
         }
     }
 
-    pub fn sum_payments(&mut self) -> u64 {
+    /// Returns the amount counting toward the budget, including the payments
+    /// in flight.
+    pub fn spent(&mut self) -> u64 {
         self.clean_old_payments();
-        self.payments.iter().map(|p| p.amount).sum()
+        self.payments
+            .iter()
+            .fold(0, |spent, p| spent.saturating_add(p.amount))
+    }
+
+    /// Returns when spending next goes down: the start of the next calendar
+    /// period, or when the oldest payment leaves a sliding window. `None` if
+    /// the budget never renews.
+    pub fn renews_at(&mut self) -> Option<u64> {
+        self.clean_old_payments();
+        let window = self.period.renewal.window()?;
+        if !self.period.calendar_aligned {
+            return Some(
+                self.payments.front().map(|p| p.time).unwrap_or_else(now) + window.as_secs(),
+            );
+        }
+
+        let date = DateTime::from_timestamp(now() as i64, 0)?.date_naive();
+        let next = self
+            .period
+            .calendar_next(self.period.calendar_start(date)?)?;
+        Some(next.and_hms_opt(0, 0, 0)?.and_utc().timestamp() as u64)
+    }
+
+    /// Returns the error message for a payment that would exceed the budget.
+    pub fn budget_exceeded_message(&mut self) -> String {
+        let renewal = self.period.renewal;
+        match self.renews_at() {
+            Some(time) => format!(
+                "Exceeded {renewal} budget, renews at {}.",
+                format_timestamp(time)
+            ),
+            None => format!("Exceeded {renewal} budget."),
+        }
     }
 }
+
+#[cfg(test)]
+mod tests;
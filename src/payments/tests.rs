@@ -0,0 +1,275 @@
+use super::*;
+use crate::test_utils::{tracker, tracker_path, TempDir};
+
+const DAY: u64 = 86_400;
+
+fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(year, month, day).unwrap()
+}
+
+fn timestamp(date: NaiveDate, hour: u32) -> u64 {
+    date.and_hms_opt(hour, 0, 0).unwrap().and_utc().timestamp() as u64
+}
+
+fn period(renewal: BudgetRenewal, calendar_aligned: bool) -> BudgetPeriod {
+    BudgetPeriod {
+        renewal,
+        calendar_aligned,
+    }
+}
+
+#[test]
+fn calendar_week_starts_on_monday() {
+    let weekly = period(BudgetRenewal::Weekly, true);
+
+    // Wednesday, Sunday and Monday of the same week
+    assert_eq!(
+        weekly.calendar_start(date(2024, 5, 15)),
+        Some(date(2024, 5, 13))
+    );
+    assert_eq!(
+        weekly.calendar_start(date(2024, 5, 19)),
+        Some(date(2024, 5, 13))
+    );
+    assert_eq!(
+        weekly.calendar_start(date(2024, 5, 13)),
+        Some(date(2024, 5, 13))
+    );
+    // a week spanning the new year
+    assert_eq!(
+        weekly.calendar_start(date(2025, 1, 1)),
+        Some(date(2024, 12, 30))
+    );
+}
+
+#[test]
+fn calendar_month_starts_on_the_first() {
+    let monthly = period(BudgetRenewal::Monthly, true);
+
+    assert_eq!(
+        monthly.calendar_start(date(2024, 5, 31)),
+        Some(date(2024, 5, 1))
+    );
+    assert_eq!(
+        monthly.calendar_start(date(2024, 2, 29)),
+        Some(date(2024, 2, 1))
+    );
+    assert_eq!(
+        monthly.calendar_start(date(2024, 5, 1)),
+        Some(date(2024, 5, 1))
+    );
+}
+
+#[test]
+fn calendar_next_crosses_boundaries() {
+    let weekly = period(BudgetRenewal::Weekly, true);
+    assert_eq!(
+        weekly.calendar_next(date(2024, 12, 30)),
+        Some(date(2025, 1, 6))
+    );
+
+    let monthly = period(BudgetRenewal::Monthly, true);
+    assert_eq!(
+        monthly.calendar_next(date(2024, 1, 1)),
+        Some(date(2024, 2, 1))
+    );
+    assert_eq!(
+        monthly.calendar_next(date(2024, 12, 1)),
+        Some(date(2025, 1, 1))
+    );
+
+    let yearly = period(BudgetRenewal::Yearly, true);
+    assert_eq!(
+        yearly.calendar_next(date(2024, 1, 1)),
+        Some(date(2025, 1, 1))
+    );
+
+    let never = period(BudgetRenewal::Never, true);
+    assert_eq!(never.calendar_next(date(2024, 1, 1)), None);
+}
+
+#[test]
+fn start_of_sliding_window() {
+    let now = timestamp(date(2024, 5, 15), 12);
+
+    assert_eq!(
+        period(BudgetRenewal::Daily, false).start(now),
+        Some(now - DAY)
+    );
+    assert_eq!(
+        period(BudgetRenewal::Weekly, false).start(now),
+        Some(now - 7 * DAY)
+    );
+    assert_eq!(period(BudgetRenewal::Never, false).start(now), None);
+}
+
+#[test]
+fn start_of_calendar_period() {
+    let now = timestamp(date(2024, 5, 15), 12);
+
+    assert_eq!(
+        period(BudgetRenewal::Daily, true).start(now),
+        Some(timestamp(date(2024, 5, 15), 0))
+    );
+    assert_eq!(
+        period(BudgetRenewal::Weekly, true).start(now),
+        Some(timestamp(date(2024, 5, 13), 0))
+    );
+    assert_eq!(
+        period(BudgetRenewal::Monthly, true).start(now),
+        Some(timestamp(date(2024, 5, 1), 0))
+    );
+    assert_eq!(
+        period(BudgetRenewal::Yearly, true).start(now),
+        Some(timestamp(date(2024, 1, 1), 0))
+    );
+    assert_eq!(period(BudgetRenewal::Never, true).start(now), None);
+}
+
+#[test]
+fn sliding_window_renews_when_oldest_payment_leaves() {
+    let dir = TempDir::new("renews-sliding");
+    let mut tracker = tracker(&dir, period(BudgetRenewal::Daily, false));
+    let time = now() - 3_600;
+    tracker.payments.push_back(Payment {
+        time,
+        amount: 1_000,
+        reservation: None,
+    });
+
+    assert_eq!(tracker.renews_at(), Some(time + DAY));
+}
+
+#[test]
+fn sliding_window_without_payments_renews_a_window_from_now() {
+    let dir = TempDir::new("renews-empty");
+    let mut tracker = tracker(&dir, period(BudgetRenewal::Weekly, false));
+
+    let before = now();
+    let renews_at = tracker.renews_at().unwrap();
+    assert!(renews_at >= before + 7 * DAY && renews_at <= now() + 7 * DAY);
+}
+
+#[test]
+fn calendar_period_renews_at_next_period() {
+    let dir = TempDir::new("renews-calendar");
+    let mut tracker = tracker(&dir, period(BudgetRenewal::Daily, true));
+
+    let renews_at = tracker.renews_at().unwrap();
+    assert_eq!(renews_at % DAY, 0);
+    assert!(renews_at > now() && renews_at <= now() + DAY);
+}
+
+#[test]
+fn total_budget_never_renews() {
+    let dir = TempDir::new("renews-never");
+    let mut tracker = tracker(&dir, period(BudgetRenewal::Never, false));
+
+    assert_eq!(tracker.renews_at(), None);
+}
+
+#[test]
+fn reserve_within_budget() {
+    let dir = TempDir::new("reserve");
+    let mut tracker = tracker(&dir, period(BudgetRenewal::Daily, false));
+
+    let reservation = tracker.reserve(6_000, 10_000).unwrap().unwrap();
+    assert_eq!(reservation.amount(), 6_000);
+    assert_eq!(tracker.spent(), 6_000);
+
+    // the reservation counts against later payments
+    assert!(tracker.reserve(5_000, 10_000).unwrap().is_none());
+    assert!(tracker.reserve(4_000, 10_000).unwrap().is_some());
+    assert_eq!(tracker.spent(), 10_000);
+}
+
+#[test]
+fn reserve_without_budget() {
+    let dir = TempDir::new("reserve-unlimited");
+    let mut tracker = tracker(&dir, period(BudgetRenewal::Daily, false));
+
+    assert!(tracker.reserve(u32::MAX as u64, 0).unwrap().is_some());
+}
+
+#[test]
+fn huge_reservation_exceeds_the_budget() {
+    let dir = TempDir::new("reserve-huge");
+    let mut tracker = tracker(&dir, period(BudgetRenewal::Daily, false));
+
+    tracker.reserve(1_000, 10_000).unwrap().unwrap();
+    assert!(tracker.reserve(u64::MAX, 10_000).unwrap().is_none());
+    assert_eq!(tracker.spent(), 1_000);
+}
+
+#[test]
+fn commit_counts_the_amount_paid() {
+    let dir = TempDir::new("commit");
+    let mut tracker = tracker(&dir, period(BudgetRenewal::Daily, false));
+
+    let reservation = tracker.reserve(6_000, 10_000).unwrap().unwrap();
+    tracker.commit(reservation, 5_200).unwrap();
+    assert_eq!(tracker.spent(), 5_200);
+    assert!(tracker.payments.iter().all(|p| p.reservation.is_none()));
+}
+
+#[test]
+fn release_frees_the_budget() {
+    let dir = TempDir::new("release");
+    let mut tracker = tracker(&dir, period(BudgetRenewal::Daily, false));
+
+    let reservation = tracker.reserve(6_000, 10_000).unwrap().unwrap();
+    tracker.release(reservation).unwrap();
+    assert_eq!(tracker.spent(), 0);
+    assert!(tracker.reserve(10_000, 10_000).unwrap().is_some());
+}
+
+#[test]
+fn reservations_survive_a_restart() {
+    let period = period(BudgetRenewal::Daily, false);
+    let dir = TempDir::new("restart");
+    let mut tracker = tracker(&dir, period);
+
+    let paid = tracker.reserve(1_000, 0).unwrap().unwrap();
+    tracker.commit(paid, 1_000).unwrap();
+    let in_flight = tracker.reserve(2_000, 0).unwrap().unwrap();
+
+    let mut reloaded = PaymentTracker::load(tracker_path(&dir), period).unwrap();
+    assert_eq!(reloaded.spent(), 3_000);
+
+    // new reservations don't reuse the id of the one in flight
+    let reservation = reloaded.reserve(500, 0).unwrap().unwrap();
+    assert_ne!(reservation.id, in_flight.id);
+}
+
+#[test]
+fn split_hands_out_shares_and_releases_the_rest() {
+    let dir = TempDir::new("split");
+    let mut tracker = tracker(&dir, period(BudgetRenewal::Daily, false));
+
+    let batch = tracker.reserve(10_000, 10_000).unwrap().unwrap();
+    let shares = tracker.split(batch, &[3_000, 2_500]).unwrap();
+
+    let amounts: Vec<_> = shares.iter().map(Reservation::amount).collect();
+    assert_eq!(amounts, [3_000, 2_500]);
+    // only the shares still count, the rest of the batch is free again
+    assert_eq!(tracker.spent(), 5_500);
+
+    // each share settles on its own
+    let mut shares = shares.into_iter();
+    tracker.commit(shares.next().unwrap(), 2_000).unwrap();
+    tracker.release(shares.next().unwrap()).unwrap();
+    assert_eq!(tracker.spent(), 2_000);
+}
+
+#[test]
+fn split_caps_shares_at_the_reserved_amount() {
+    let dir = TempDir::new("split-capped");
+    let mut tracker = tracker(&dir, period(BudgetRenewal::Daily, false));
+
+    let batch = tracker.reserve(4_000, 0).unwrap().unwrap();
+    let shares = tracker.split(batch, &[3_000, 3_000]).unwrap();
+
+    let amounts: Vec<_> = shares.iter().map(Reservation::amount).collect();
+    assert_eq!(amounts, [3_000, 1_000]);
+    assert_eq!(tracker.spent(), 4_000);
+}
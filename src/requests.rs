@@ -0,0 +1,138 @@
+use std::collections::HashSet;
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Context;
+use nostr::{Event, EventId, Timestamp};
+use serde::{Deserialize, Serialize};
+
+/// A handled request and the responses sent for it.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct HandledRequest {
+    /// Unix timestamp the app gave the request, in seconds
+    #[serde(default)]
+    created_at: u64,
+    responses: Vec<Event>,
+}
+
+/// Persistent record of the requests we handled and the responses we sent,
+/// so requests redelivered by relays or replayed by an attacker are answered
+/// with the original responses instead of being executed again.
+pub struct RequestStore {
+    dir: PathBuf,
+}
+
+impl RequestStore {
+    pub fn new(dir: PathBuf) -> Result<Self, anyhow::Error> {
+        create_dir_all(&dir).context("Could not create directory")?;
+        Ok(RequestStore { dir })
+    }
+
+    fn path(&self, request_id: &EventId) -> PathBuf {
+        self.dir.join(format!("{}.json", request_id.to_hex()))
+    }
+
+    /// Records that a request is being handled. Returns `false` if it was
+    /// already recorded, in which case it must not be executed again. The
+    /// record is written before the request runs so a crash mid-payment can't
+    /// lead to paying twice.
+    pub fn begin(&self, request: &Event) -> Result<bool, anyhow::Error> {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.path(&request.id))
+        {
+            Ok(mut file) => {
+                let record = HandledRequest {
+                    created_at: request.created_at.as_u64(),
+                    responses: Vec::new(),
+                };
+                let json_str =
+                    serde_json::to_string(&record).context("Could not serialize data")?;
+                file.write_all(json_str.as_bytes())
+                    .context("Could not write to file")?;
+                file.sync_all().context("Could not sync file")?;
+                Ok(true)
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(e).context("Could not create file"),
+        }
+    }
+
+    fn read(&self, request_id: &EventId) -> Result<HandledRequest, anyhow::Error> {
+        let file = File::open(self.path(request_id)).context("Could not open file")?;
+        let reader = BufReader::new(file);
+        serde_json::from_reader(reader).context("Could not parse JSON")
+    }
+
+    /// Returns the responses sent for a handled request.
+    pub fn responses(&self, request_id: &EventId) -> Result<Vec<Event>, anyhow::Error> {
+        Ok(self.read(request_id)?.responses)
+    }
+
+    /// Records a response sent for a request.
+    pub fn add_response(&self, request_id: &EventId, response: Event) -> Result<(), anyhow::Error> {
+        let mut request = self.read(request_id)?;
+        request.responses.push(response);
+
+        let json_str = serde_json::to_string(&request).context("Could not serialize data")?;
+        let path = self.path(request_id);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path).context("Could not create file")?;
+        file.write_all(json_str.as_bytes())
+            .context("Could not write to file")?;
+        file.sync_all().context("Could not sync file")?;
+        rename(&tmp_path, &path).context("Could not move file into place")?;
+
+        Ok(())
+    }
+
+    /// Removes the records of requests both created and last touched more
+    /// than `max_age` ago, except the ones still being handled. Requests that
+    /// old are rejected anyway, so replays of them don't need their records.
+    /// Requests dated in the future keep their records until that time is
+    /// `max_age` in the past.
+    pub fn prune(
+        &self,
+        max_age: Duration,
+        active_requests: &HashSet<EventId>,
+    ) -> Result<usize, anyhow::Error> {
+        let mut pruned = 0;
+        for entry in read_dir(&self.dir).context("Could not read directory")? {
+            let path = entry.context("Could not read directory")?.path();
+            let active = path
+                .file_stem()
+                .and_then(|stem| EventId::from_hex(stem.to_string_lossy().as_ref()).ok())
+                .map_or(false, |id| active_requests.contains(&id));
+            if active {
+                continue;
+            }
+
+            let age = path
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .map(|modified| modified.elapsed().unwrap_or_default());
+            if !matches!(age, Ok(age) if age > max_age) {
+                continue;
+            }
+
+            // records that can't be read are as good as gone
+            let created_at = File::open(&path)
+                .ok()
+                .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+                .map_or(0, |request: HandledRequest| request.created_at);
+            let created_age = Timestamp::now().as_u64().saturating_sub(created_at);
+            if created_age > max_age.as_secs() {
+                remove_file(&path).context("Could not remove file")?;
+                pruned += 1;
+            }
+        }
+
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod tests;
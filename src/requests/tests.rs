@@ -0,0 +1,93 @@
+use std::time::SystemTime;
+
+use nostr::{EventBuilder, Keys, Kind};
+
+use super::*;
+use crate::test_utils::TempDir;
+
+/// Returns a store in a fresh directory in `dir`.
+fn store(dir: &TempDir) -> RequestStore {
+    RequestStore::new(dir.path().join("requests")).unwrap()
+}
+
+/// Returns a request created `age` seconds ago, or in the future for a
+/// negative age.
+fn request(age: i64) -> Event {
+    let created_at = Timestamp::from((Timestamp::now().as_u64() as i64 - age) as u64);
+    EventBuilder::new(Kind::WalletConnectRequest, "", [])
+        .custom_created_at(created_at)
+        .to_event(&Keys::generate())
+        .unwrap()
+}
+
+fn response(content: &str) -> Event {
+    EventBuilder::new(Kind::WalletConnectResponse, content, [])
+        .to_event(&Keys::generate())
+        .unwrap()
+}
+
+/// Makes the record of a request look last touched `age` ago.
+fn touch(store: &RequestStore, request_id: &EventId, age: Duration) {
+    File::options()
+        .write(true)
+        .open(store.path(request_id))
+        .unwrap()
+        .set_modified(SystemTime::now() - age)
+        .unwrap();
+}
+
+#[test]
+fn second_begin_is_refused() {
+    let dir = TempDir::new("begin");
+    let store = store(&dir);
+    let request = request(0);
+
+    assert!(store.begin(&request).unwrap());
+    assert!(!store.begin(&request).unwrap());
+    assert!(store.begin(&self::request(0)).unwrap());
+}
+
+#[test]
+fn responses_round_trip() {
+    let dir = TempDir::new("responses");
+    let store = store(&dir);
+    let request = request(0);
+    store.begin(&request).unwrap();
+    assert!(store.responses(&request.id).unwrap().is_empty());
+
+    let responses = vec![response("first"), response("second")];
+    for response in &responses {
+        store.add_response(&request.id, response.clone()).unwrap();
+    }
+
+    let stored = store.responses(&request.id).unwrap();
+    assert_eq!(stored, responses);
+}
+
+#[test]
+fn prune_removes_only_old_inactive_records() {
+    let dir = TempDir::new("prune");
+    let store = store(&dir);
+    let max_age = Duration::from_secs(600);
+    let hour = Duration::from_secs(3_600);
+
+    let recent = request(0);
+    let old = request(3_600);
+    let active = request(3_600);
+    // dated in the future by a clock running ahead, touched long ago
+    let future = request(-3_600);
+    for request in [&recent, &old, &active, &future] {
+        store.begin(request).unwrap();
+    }
+    for request in [&old, &active, &future] {
+        touch(&store, &request.id, hour);
+    }
+
+    let active_requests = HashSet::from([active.id]);
+    assert_eq!(store.prune(max_age, &active_requests).unwrap(), 1);
+
+    assert!(!store.path(&old.id).exists());
+    for request in [&recent, &active, &future] {
+        assert!(store.path(&request.id).exists());
+    }
+}
@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::fs::{create_dir_all, rename, File};
+use std::io::{BufReader, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use fedimint_core::core::OperationId;
+
+/// When operations settled by our clock, persisted to disk. The operation log
+/// only records the outcome of an operation, not when it was reached, so
+/// without a recorded time transactions are reported without one.
+pub struct Settlements {
+    /// Unix timestamps in seconds, by hex operation id
+    times: HashMap<String, u64>,
+    path: PathBuf,
+}
+
+impl Settlements {
+    /// Loads the settlement times recorded in the specified file, starting
+    /// with none if the file does not exist.
+    pub fn load(path: PathBuf) -> Result<Self, anyhow::Error> {
+        let times = match File::open(&path) {
+            Ok(file) => {
+                let reader = BufReader::new(file);
+                serde_json::from_reader(reader).context("Could not parse JSON")?
+            }
+            Err(_) => HashMap::new(),
+        };
+
+        Ok(Settlements { times, path })
+    }
+
+    /// Records that an operation settled now. An operation seen settled
+    /// before keeps its first time.
+    pub fn record(&mut self, operation_id: &OperationId) -> Result<(), anyhow::Error> {
+        let key = hex::encode(operation_id.0);
+        if self.times.contains_key(&key) {
+            return Ok(());
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.times.insert(key, now);
+        self.write()
+    }
+
+    /// Returns when an operation settled, `None` if that wasn't recorded.
+    pub fn settled_at(&self, operation_id: &OperationId) -> Option<u64> {
+        self.times.get(&hex::encode(operation_id.0)).copied()
+    }
+
+    /// Writes the times to a temporary file first and moves it into place,
+    /// so a crash mid-write can't lose them.
+    fn write(&self) -> Result<(), anyhow::Error> {
+        let json_str = serde_json::to_string(&self.times).context("Could not serialize data")?;
+
+        if let Some(parent) = self.path.parent() {
+            create_dir_all(parent).context("Could not create directory")?;
+        }
+
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp_path).context("Could not create file")?;
+        file.write_all(json_str.as_bytes())
+            .context("Could not write to file")?;
+        file.sync_all().context("Could not sync file")?;
+        rename(&tmp_path, &self.path).context("Could not move file into place")?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+use std::fs::{create_dir_all, remove_dir_all};
+use std::path::{Path, PathBuf};
+
+use crate::payments::{BudgetPeriod, PaymentTracker};
+
+/// A fresh directory in the temporary directory, removed with everything in
+/// it when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("pnyxtr-{}-{name}", std::process::id()));
+        let _ = remove_dir_all(&path);
+        create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
+
+/// Returns the path a tracker in `dir` is persisted to.
+pub fn tracker_path(dir: &TempDir) -> PathBuf {
+    dir.path().join("payments.json")
+}
+
+/// Returns a tracker persisted to a fresh file in `dir`.
+pub fn tracker(dir: &TempDir, period: BudgetPeriod) -> PaymentTracker {
+    PaymentTracker::load(tracker_path(dir), period).unwrap()
+}